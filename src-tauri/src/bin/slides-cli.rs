@@ -0,0 +1,182 @@
+//! Runs the same processing as the app without a window, so whole courses can be processed on a server.
+//!
+//! Results go in the same layout as the app's, so pointing `--output` at the app's videos folder (the default) lets
+//! the app open them straight away.
+
+use std::{
+	env, fs,
+	path::PathBuf,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant}
+};
+
+use anyhow::{bail, Context, Result};
+use app_lib::{
+	pipeline::{is_processed, output_folder, process, Metadata, Reporter},
+	whisper::ModelCache,
+	AppSettings, BasicProgress, ExtendedProgress, Progress
+};
+use serde_json::from_slice;
+use tauri::async_runtime;
+
+static USAGE: &str = "Usage: slides-cli [--output <folder>] [--data <folder>] [--settings <file>] <video>...
+
+  --output <folder>   Where to put results, defaults to the app's videos folder
+  --data <folder>     Where Whisper models are kept, defaults to the app's data folder
+  --settings <file>   Settings to use, defaults to the app's settings";
+
+// Must match the identifier in tauri.conf.json so the app's data folder is found
+static IDENTIFIER: &str = "app.j.slides";
+
+/// Prints the state of every stage on one line, since transcription and slide detection run at the same time
+#[derive(Default)]
+struct TerminalReporter {
+	state: Mutex<(Vec<(&'static str, String)>, Option<Instant>)>
+}
+
+impl TerminalReporter {
+	fn finish(&self) {
+		let mut state = self.state.lock().unwrap();
+
+		state.0.clear();
+		state.1 = None;
+
+		eprintln!();
+	}
+}
+
+impl Reporter for TerminalReporter {
+	fn report(&self, progress: Progress) -> Result<()> {
+		let (stage, progress) = match progress {
+			Progress::Transcoding(BasicProgress::Started) => ("Transcoding", ExtendedProgress::Preparing),
+			Progress::Transcoding(BasicProgress::Done) => ("Transcoding", ExtendedProgress::Done),
+			Progress::Downloading(x) => ("Downloading model", x),
+			Progress::Transcribing(x) => ("Transcribing", x),
+			Progress::Processing(x) => ("Processing", x),
+			Progress::GatheringPreviews(x) => ("Gathering previews", x),
+			Progress::Summarising(x) => ("Summarising", x)
+		};
+
+		let (status, urgent) = match progress {
+			ExtendedProgress::Preparing => ("preparing".into(), true),
+			ExtendedProgress::Progress(fraction, remaining) => (
+				format!("{:.1}% ({:.0}s left)", fraction * 100.0, remaining.max(0.0)),
+				false
+			),
+			ExtendedProgress::Done => ("done".into(), true)
+		};
+
+		let mut state = self.state.lock().unwrap();
+		let (stages, last_print) = &mut *state;
+
+		match stages.iter_mut().find(|(name, _)| *name == stage) {
+			Some((_, x)) => *x = status,
+			None => stages.push((stage, status))
+		}
+
+		if urgent || last_print.is_none_or(|x| Instant::now() - x > Duration::from_millis(250)) {
+			*last_print = Some(Instant::now());
+
+			eprint!(
+				"\r\x1b[K{}",
+				stages
+					.iter()
+					.map(|(name, status)| format!("{name}: {status}"))
+					.collect::<Vec<_>>()
+					.join(" | ")
+			);
+		}
+
+		Ok(())
+	}
+}
+
+fn main() -> Result<()> {
+	let (mut output, mut data, mut settings, mut videos) = (None, None, None, vec![]);
+
+	let mut args = env::args().skip(1);
+
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--output" => output = Some(PathBuf::from(args.next().context("Missing folder after --output")?)),
+			"--data" => data = Some(PathBuf::from(args.next().context("Missing folder after --data")?)),
+			"--settings" => settings = Some(PathBuf::from(args.next().context("Missing file after --settings")?)),
+			"-h" | "--help" => {
+				println!("{USAGE}");
+				return Ok(());
+			}
+			_ => videos.push(PathBuf::from(arg))
+		}
+	}
+
+	if videos.is_empty() {
+		bail!("No videos given\n\n{USAGE}");
+	}
+
+	let data = data
+		.or_else(|| tauri::api::path::data_dir().map(|x| x.join(IDENTIFIER)))
+		.context("Couldn't get app data folder")?;
+
+	let output = output.unwrap_or_else(|| data.join("videos"));
+
+	let settings_path = settings.unwrap_or_else(|| data.join("settings.json"));
+
+	let settings = if settings_path.exists() {
+		from_slice::<AppSettings>(&fs::read(&settings_path).context("Couldn't read settings")?)
+			.context("Couldn't deserialise settings")?
+	} else {
+		AppSettings::default()
+	};
+
+	fs::create_dir_all(&data).context("Couldn't ensure data folder")?;
+
+	let reporter = Arc::new(TerminalReporter::default());
+	// Loaded once for all the videos
+	let models = ModelCache::default();
+
+	let mut failed = 0;
+
+	for video in &videos {
+		let result = async_runtime::block_on(async {
+			// Per-video settings are keyed by the absolute path the app sees
+			let video_path = fs::canonicalize(video).context("Couldn't find video")?;
+
+			let output_path = output_folder(&output, &video_path)?;
+
+			if is_processed(&output_path, &video_path, &settings) {
+				eprintln!("{} was already processed", video.display());
+			} else {
+				eprintln!("Processing {}", video.display());
+
+				process(&video_path, &output_path, &data, &models, &settings, reporter.clone()).await?;
+
+				reporter.finish();
+
+				if fs::read(output_path.join("metadata.json"))
+					.ok()
+					.and_then(|x| from_slice::<Metadata>(&x).ok())
+					.is_some_and(|x| x.no_audio)
+				{
+					eprintln!("{} has no audio, only found slides", video.display());
+				}
+			}
+
+			println!("{}\t{}", video.display(), output_path.display());
+
+			anyhow::Ok(())
+		});
+
+		if let Err(e) = result {
+			failed += 1;
+
+			reporter.finish();
+			eprintln!("Couldn't process {}: {e:?}", video.display());
+		}
+	}
+
+	if failed > 0 {
+		bail!("{failed} of {} videos couldn't be processed", videos.len());
+	}
+
+	Ok(())
+}
@@ -3,12 +3,17 @@ use std::{fs, ops::Deref, path::PathBuf};
 use anyhow::{Context, Result};
 use arc_swap::ArcSwap;
 use fn_error_context::context;
-use macros::tauri_command;
+use macros::{async_tauri_command, tauri_command};
 use serde_json::to_string;
 use tauri::{AppHandle, Manager};
 use tryvial::try_fn;
 
-use crate::AppSettings;
+use app_lib::{
+	AppSettings,
+	models::{self, ModelEntry},
+	transcode::{self, AudioStream},
+	whisper::ModelCache
+};
 
 #[tauri_command]
 #[try_fn]
@@ -29,6 +34,11 @@ fn save_settings(app: &AppHandle, settings: AppSettings) -> Result<()> {
 		to_string(&settings)?
 	)?;
 
+	// Free the old model rather than waiting for it to time out
+	if app.state::<ArcSwap<AppSettings>>().load().model() != settings.model() {
+		app.state::<ModelCache>().clear();
+	}
+
 	app.state::<ArcSwap<AppSettings>>().store(settings.into());
 }
 
@@ -38,3 +48,38 @@ fn save_settings(app: &AppHandle, settings: AppSettings) -> Result<()> {
 fn save_current_time(data_path: PathBuf, time: f64) -> Result<()> {
 	fs::write(data_path.join("current_time.txt"), time.to_string())?;
 }
+
+#[tauri_command]
+#[try_fn]
+#[context("Failed to list models")]
+fn list_models(app: &AppHandle) -> Result<Vec<ModelEntry>> {
+	models::list_models(
+		&app.path_resolver()
+			.app_data_dir()
+			.context("Couldn't get app data dir")?
+	)?
+}
+
+#[async_tauri_command]
+#[try_fn]
+#[context("Failed to import model")]
+async fn import_model(app: &AppHandle, model_path: &PathBuf) -> Result<String> {
+	let name = models::import_model(
+		&app.path_resolver()
+			.app_data_dir()
+			.context("Couldn't get app data dir")?,
+		model_path
+	)?;
+
+	// The import may have replaced the file of a loaded model
+	app.state::<ModelCache>().clear();
+
+	name
+}
+
+#[tauri_command]
+#[try_fn]
+#[context("Failed to list audio streams")]
+fn list_audio_streams(video_path: PathBuf) -> Result<Vec<AudioStream>> {
+	transcode::list_audio_streams(&video_path)?
+}
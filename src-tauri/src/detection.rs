@@ -0,0 +1,302 @@
+use std::ops::Range;
+
+use delta_e::DE2000;
+use ndarray::s;
+use rayon::{
+	iter::{IndexedParallelIterator, ParallelIterator},
+	slice::ParallelSlice
+};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use video_rs::Frame;
+
+/// Decides whether two decoded frames show the same slide
+pub trait SlideChangeDetector: Send + Sync {
+	/// Recorded in the output folder so it's known how the regions were made
+	fn name(&self) -> &'static str;
+
+	/// Similarity between two frames, where 1.0 means identical
+	fn similarity(&self, x: &Frame, y: &Frame) -> f32;
+
+	/// Works out up front whatever the detector compares, for a frame that's going to be compared more than once
+	fn prepare(&self, frame: Frame) -> PreparedFrame {
+		PreparedFrame {
+			frame,
+			fingerprint: None
+		}
+	}
+
+	/// Same as `similarity`, reusing what `prepare` worked out
+	fn prepared_similarity(&self, x: &PreparedFrame, y: &PreparedFrame) -> f32 {
+		self.similarity(&x.frame, &y.frame)
+	}
+
+	/// Frames less similar than this are treated as a slide change
+	fn threshold(&self) -> f32;
+
+	/// Size frames should be scaled down to while decoding, if the detector doesn't need full resolution
+	fn decode_size(&self) -> Option<(u32, u32)> {
+		None
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum Detector {
+	#[default]
+	DeltaE,
+	PerceptualHash {
+		// Number of differing hash bits at which frames count as different slides
+		max_distance: u32
+	}
+}
+
+impl Detector {
+	pub fn build(self) -> Box<dyn SlideChangeDetector> {
+		match self {
+			Detector::DeltaE => Box::new(DeltaE),
+			Detector::PerceptualHash { max_distance } => Box::new(PerceptualHash { max_distance })
+		}
+	}
+}
+
+/// Rectangle in fractions of the frame's width and height, so it applies at any decode size
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Type)]
+pub struct Rect {
+	pub x: f32,
+	pub y: f32,
+	pub width: f32,
+	pub height: f32
+}
+
+impl Rect {
+	fn bounds(&self, width: usize, height: usize) -> (Range<usize>, Range<usize>) {
+		let to_pixels = |fraction: f32, size: usize| (fraction.clamp(0.0, 1.0) * size as f32).round() as usize;
+
+		let (left, top) = (to_pixels(self.x, width), to_pixels(self.y, height));
+		let (right, bottom) = (
+			to_pixels(self.x + self.width, width).max(left),
+			to_pixels(self.y + self.height, height).max(top)
+		);
+
+		(left..right, top..bottom)
+	}
+}
+
+/// The part of the frame that's compared when looking for slide changes
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default, Type)]
+pub struct RegionOfInterest {
+	#[serde(default)]
+	pub crop: Option<Rect>,
+	// Relative to the whole frame, not the crop
+	#[serde(default)]
+	pub exclusions: Vec<Rect>
+}
+
+impl RegionOfInterest {
+	/// Blanks out excluded areas and crops the frame
+	pub fn apply(&self, mut frame: Frame) -> Frame {
+		let (height, width, _) = frame.dim();
+
+		for exclusion in &self.exclusions {
+			let (columns, rows) = exclusion.bounds(width, height);
+
+			frame.slice_mut(s![rows, columns, ..]).fill(0);
+		}
+
+		self.crop(frame)
+	}
+
+	pub fn crop(&self, frame: Frame) -> Frame {
+		match self.crop {
+			Some(crop) => {
+				let (height, width, _) = frame.dim();
+				let (columns, rows) = crop.bounds(width, height);
+
+				frame.slice(s![rows, columns, ..]).to_owned()
+			}
+
+			None => frame
+		}
+	}
+}
+
+/// Compares every pixel of both frames with CIEDE2000
+pub struct DeltaE;
+
+impl SlideChangeDetector for DeltaE {
+	fn name(&self) -> &'static str {
+		"deltaE"
+	}
+
+	// Average similarity of colours based on Oklab
+	fn similarity(&self, x: &Frame, y: &Frame) -> f32 {
+		let (x, y) = (x.as_slice().unwrap(), y.as_slice().unwrap());
+
+		(100.0
+			- (x.par_chunks_exact(3)
+				.zip(y.par_chunks_exact(3))
+				.map(|(one, two)| DE2000::from_rgb(one.try_into().unwrap(), two.try_into().unwrap()))
+				.sum::<f32>()
+				/ x.len() as f32))
+			/ 100.0
+	}
+
+	fn threshold(&self) -> f32 {
+		0.99
+	}
+}
+
+/// Average luma of each cell in a grid laid over the frame, row by row
+pub fn luma_grid(frame: &Frame, columns: usize, rows: usize) -> Vec<f32> {
+	let (height, width, _) = frame.dim();
+
+	let mut sums = vec![0u64; columns * rows];
+	let mut counts = vec![0u64; columns * rows];
+
+	for (y, row) in frame.outer_iter().enumerate() {
+		let cell_y = y * rows / height;
+
+		for (x, pixel) in row.outer_iter().enumerate() {
+			let cell = cell_y * columns + x * columns / width;
+
+			sums[cell] += (299 * pixel[0] as u64 + 587 * pixel[1] as u64 + 114 * pixel[2] as u64) / 1000;
+			counts[cell] += 1;
+		}
+	}
+
+	sums.into_iter()
+		.zip(counts)
+		.map(|(sum, count)| sum as f32 / count.max(1) as f32)
+		.collect()
+}
+
+/// Whether `next` only adds content to `previous` on top of its background, like a bullet point being revealed
+pub fn is_build_step(previous: &Frame, next: &Frame) -> bool {
+	const COLUMNS: usize = 128;
+	const ROWS: usize = 72;
+	const TOLERANCE: f32 = 12.0;
+
+	let (previous, next) = (luma_grid(previous, COLUMNS, ROWS), luma_grid(next, COLUMNS, ROWS));
+
+	// The most common brightness is taken to be the slide background
+	let mut histogram = [0usize; 32];
+	for luma in &previous {
+		histogram[(*luma as usize / 8).min(31)] += 1;
+	}
+	let background = (0..32).max_by_key(|bin| histogram[*bin]).unwrap() as f32 * 8.0 + 4.0;
+
+	let (mut changed, mut changed_on_background, mut content, mut content_kept) = (0, 0, 0, 0);
+
+	for (previous, next) in previous.iter().zip(&next) {
+		let is_changed = (previous - next).abs() > TOLERANCE;
+		let is_background = (previous - background).abs() <= TOLERANCE;
+
+		if is_changed {
+			changed += 1;
+
+			if is_background {
+				changed_on_background += 1;
+			}
+		}
+
+		if !is_background {
+			content += 1;
+
+			if !is_changed {
+				content_kept += 1;
+			}
+		}
+	}
+
+	changed > 0 && changed_on_background as f32 >= changed as f32 * 0.9 && content_kept as f32 >= content as f32 * 0.95
+}
+
+const HASH_WIDTH: usize = 33;
+const HASH_HEIGHT: usize = 32;
+const HASH_BITS: usize = (HASH_WIDTH - 1) * HASH_HEIGHT;
+
+/// Difference hash of a frame, which also serves to recognise a slide when the lecturer comes back to it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Fingerprint([u64; HASH_BITS / 64]);
+
+impl Fingerprint {
+	// dHash over a grid of average luma values; a small margin keeps flat areas from flipping bits on noise
+	pub fn new(frame: &Frame) -> Self {
+		let grid = luma_grid(frame, HASH_WIDTH, HASH_HEIGHT);
+
+		let mut hash = [0u64; HASH_BITS / 64];
+
+		for y in 0..HASH_HEIGHT {
+			for x in 0..HASH_WIDTH - 1 {
+				if grid[y * HASH_WIDTH + x] > grid[y * HASH_WIDTH + x + 1] + 2.0 {
+					let bit = y * (HASH_WIDTH - 1) + x;
+					hash[bit / 64] |= 1 << (bit % 64);
+				}
+			}
+		}
+
+		Self(hash)
+	}
+
+	pub fn distance(&self, other: &Self) -> u32 {
+		self.0
+			.iter()
+			.zip(other.0)
+			.map(|(one, two)| (one ^ two).count_ones())
+			.sum()
+	}
+
+	/// Whether both fingerprints are close enough to be the same slide
+	pub fn matches(&self, other: &Self) -> bool {
+		self.distance(other) as usize <= HASH_BITS / 100
+	}
+}
+
+/// A frame along with what a detector compares of it
+#[derive(Clone)]
+pub struct PreparedFrame {
+	pub frame: Frame,
+	fingerprint: Option<Fingerprint>
+}
+
+fn hash_similarity(x: &Fingerprint, y: &Fingerprint) -> f32 {
+	1.0 - x.distance(y) as f32 / HASH_BITS as f32
+}
+
+/// Compares difference hashes of frames, which is far cheaper than comparing every pixel
+pub struct PerceptualHash {
+	pub max_distance: u32
+}
+
+impl SlideChangeDetector for PerceptualHash {
+	fn name(&self) -> &'static str {
+		"perceptualHash"
+	}
+
+	fn similarity(&self, x: &Frame, y: &Frame) -> f32 {
+		hash_similarity(&Fingerprint::new(x), &Fingerprint::new(y))
+	}
+
+	fn prepare(&self, frame: Frame) -> PreparedFrame {
+		PreparedFrame {
+			fingerprint: Some(Fingerprint::new(&frame)),
+			frame
+		}
+	}
+
+	fn prepared_similarity(&self, x: &PreparedFrame, y: &PreparedFrame) -> f32 {
+		let fingerprint = |x: &PreparedFrame| x.fingerprint.unwrap_or_else(|| Fingerprint::new(&x.frame));
+
+		hash_similarity(&fingerprint(x), &fingerprint(y))
+	}
+
+	fn threshold(&self) -> f32 {
+		// Exactly `max_distance` differing bits still counts as the same slide
+		1.0 - (self.max_distance as f32 + 0.5) / HASH_BITS as f32
+	}
+
+	fn decode_size(&self) -> Option<(u32, u32)> {
+		Some((480, 270))
+	}
+}
@@ -0,0 +1,454 @@
+use std::f32::consts::PI;
+
+use crate::{transcode::SAMPLE_RATE, whisper::Utterance};
+
+// 32 ms frames, taking every other one, which is plenty to tell voices apart
+const FRAME: usize = 512;
+const HOP: usize = 1024;
+// Points the spectrum is sampled at, spread evenly on the mel scale between these frequencies in Hz
+const BANDS: usize = 20;
+const LOWEST: f32 = 100.0;
+const HIGHEST: f32 = 4000.0;
+
+// Segments shorter than this, in seconds, say too little about the voice and go with the speaker before
+const MIN_DURATION: f32 = 1.0;
+// How alike a segment has to be to a speaker's average voice to be counted as them, by cosine similarity
+const MIN_SIMILARITY: f32 = 0.5;
+// Speakers with less speech than this, in seconds, are most likely the same voice in other surroundings
+const MIN_SPEAKING_TIME: f32 = 10.0;
+// Rounds of moving segments to the speaker they're closest to once all speakers are known
+const ITERATIONS: usize = 5;
+// How far apart two speakers' average voices have to be before scaling, as the root mean square of the difference in
+// log power, since scaling makes the slightest differences within one voice look like two
+const MIN_DIFFERENCE: f32 = 0.5;
+
+fn mel(hz: f32) -> f32 {
+	2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn hz(mel: f32) -> f32 {
+	700.0 * (10.0_f32.powf(mel / 2595.0) - 1.0)
+}
+
+// Power of the frame at one frequency, without working out the whole spectrum
+fn goertzel(frame: &[f32], frequency: f32) -> f32 {
+	let coefficient = 2.0 * (2.0 * PI * frequency / SAMPLE_RATE as f32).cos();
+
+	let (mut previous, mut before) = (0.0, 0.0);
+
+	for (idx, x) in frame.iter().enumerate() {
+		// Hann window
+		let x = x * (0.5 - 0.5 * (2.0 * PI * idx as f32 / (frame.len() - 1) as f32).cos());
+
+		(previous, before) = (x + coefficient * previous - before, previous);
+	}
+
+	previous * previous + before * before - coefficient * previous * before
+}
+
+// Mean and spread of the spectrum's shape over the segment, or `None` if it's all silence
+fn embed(samples: &[f32], frequencies: &[f32]) -> Option<Vec<f32>> {
+	let mut frames = 0;
+	let mut sum = [0.0; BANDS];
+	let mut squares = [0.0; BANDS];
+
+	for frame in samples.chunks(HOP).filter(|x| x.len() >= FRAME).map(|x| &x[..FRAME]) {
+		if frame.iter().map(|x| x * x).sum::<f32>() < 1e-6 {
+			continue;
+		}
+
+		let levels = frequencies
+			.iter()
+			.map(|x| goertzel(frame, *x).max(1e-10).ln())
+			.collect::<Vec<_>>();
+
+		// Only the shape counts, not how loud it is
+		let mean = levels.iter().sum::<f32>() / BANDS as f32;
+
+		for (band, level) in levels.iter().enumerate() {
+			sum[band] += level - mean;
+			squares[band] += (level - mean) * (level - mean);
+		}
+
+		frames += 1;
+	}
+
+	if frames == 0 {
+		return None;
+	}
+
+	let means = sum.iter().map(|x| x / frames as f32).collect::<Vec<_>>();
+	let deviations = squares
+		.iter()
+		.zip(&means)
+		.map(|(x, mean)| (x / frames as f32 - mean * mean).max(0.0).sqrt());
+
+	Some(means.iter().copied().chain(deviations).collect())
+}
+
+fn similarity(x: &[f32], y: &[f32]) -> f32 {
+	let dot = x.iter().zip(y).map(|(x, y)| x * y).sum::<f32>();
+	let norms = x.iter().map(|x| x * x).sum::<f32>().sqrt() * y.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+	dot / norms.max(1e-10)
+}
+
+fn difference(x: &[f32], y: &[f32]) -> f32 {
+	(x.iter().zip(y).map(|(x, y)| (x - y).powi(2)).sum::<f32>() / x.len() as f32).sqrt()
+}
+
+fn closest(embedding: &[f32], centroids: &[Vec<f32>]) -> Option<(usize, f32)> {
+	centroids
+		.iter()
+		.enumerate()
+		.map(|(idx, x)| (idx, similarity(embedding, x)))
+		.max_by(|x, y| x.1.total_cmp(&y.1))
+}
+
+// Average voice of each speaker, leaving out ones with nothing assigned and renumbering the rest
+fn centroids(embeddings: &[Option<Vec<f32>>], speakers: &mut [Option<usize>]) -> Vec<Vec<f32>> {
+	let mut sums: Vec<(Vec<f32>, usize)> = vec![];
+
+	for (embedding, speaker) in embeddings.iter().zip(speakers.iter()) {
+		let (Some(embedding), Some(speaker)) = (embedding, speaker) else {
+			continue;
+		};
+
+		if sums.len() <= *speaker {
+			sums.resize(speaker + 1, (vec![0.0; embedding.len()], 0));
+		}
+
+		for (sum, x) in sums[*speaker].0.iter_mut().zip(embedding) {
+			*sum += x;
+		}
+
+		sums[*speaker].1 += 1;
+	}
+
+	let renumbered = sums
+		.iter()
+		.scan(0, |next, (_, count)| {
+			Some((*count > 0).then(|| {
+				*next += 1;
+				*next - 1
+			}))
+		})
+		.collect::<Vec<_>>();
+
+	for speaker in speakers.iter_mut() {
+		*speaker = speaker.and_then(|x| renumbered[x]);
+	}
+
+	sums.into_iter()
+		.filter(|(_, count)| *count > 0)
+		.map(|(sum, count)| sum.into_iter().map(|x| x / count as f32).collect())
+		.collect()
+}
+
+/// Works out who said each segment by clustering how their voices sound, and labels them "Speaker 1", "Speaker 2"
+/// and so on in the order they first speak. Returns `None` if there's only one speaker.
+pub fn diarize(samples: &[f32], segments: &[Utterance]) -> Option<Vec<String>> {
+	let frequencies = (0..BANDS)
+		.map(|idx| hz(mel(LOWEST) + (mel(HIGHEST) - mel(LOWEST)) * idx as f32 / (BANDS - 1) as f32))
+		.collect::<Vec<_>>();
+
+	let to_sample = |centis: i64| (centis.max(0) as usize * SAMPLE_RATE as usize / 100).min(samples.len());
+
+	let mut embeddings = segments
+		.iter()
+		.map(|x| {
+			((x.end - x.start) as f32 / 100.0 >= MIN_DURATION)
+				.then(|| {
+					embed(
+						&samples[to_sample(x.start)..to_sample(x.end).max(to_sample(x.start))],
+						&frequencies
+					)
+				})
+				.flatten()
+		})
+		.collect::<Vec<_>>();
+
+	let unscaled = embeddings.clone();
+
+	// Each dimension counts the same, however much it varies
+	let embedded = embeddings.iter().flatten().collect::<Vec<_>>();
+
+	if embedded.is_empty() {
+		return None;
+	}
+
+	let dimensions = embedded[0].len();
+
+	let means = (0..dimensions)
+		.map(|idx| embedded.iter().map(|x| x[idx]).sum::<f32>() / embedded.len() as f32)
+		.collect::<Vec<_>>();
+	let deviations = (0..dimensions)
+		.map(|idx| {
+			(embedded.iter().map(|x| (x[idx] - means[idx]).powi(2)).sum::<f32>() / embedded.len() as f32)
+				.sqrt()
+				.max(1e-6)
+		})
+		.collect::<Vec<_>>();
+
+	for embedding in embeddings.iter_mut().flatten() {
+		for (idx, x) in embedding.iter_mut().enumerate() {
+			*x = (*x - means[idx]) / deviations[idx];
+		}
+	}
+
+	// A new speaker whenever a segment isn't like anyone so far
+	let mut speakers: Vec<Option<usize>> = vec![None; segments.len()];
+	let mut centroids_so_far: Vec<(Vec<f32>, usize)> = vec![];
+
+	for (idx, embedding) in embeddings.iter().enumerate() {
+		let Some(embedding) = embedding else {
+			continue;
+		};
+
+		let best = centroids_so_far
+			.iter()
+			.enumerate()
+			.map(|(idx, (x, _))| (idx, similarity(embedding, x)))
+			.max_by(|x, y| x.1.total_cmp(&y.1))
+			.filter(|(_, x)| *x >= MIN_SIMILARITY);
+
+		match best {
+			Some((speaker, _)) => {
+				let (centroid, count) = &mut centroids_so_far[speaker];
+				*count += 1;
+
+				for (x, y) in centroid.iter_mut().zip(embedding) {
+					*x += (y - *x) / *count as f32;
+				}
+
+				speakers[idx] = Some(speaker);
+			}
+			None => {
+				speakers[idx] = Some(centroids_so_far.len());
+				centroids_so_far.push((embedding.clone(), 1));
+			}
+		}
+	}
+
+	let mut centroids = centroids(&embeddings, &mut speakers);
+
+	for _ in 0..ITERATIONS {
+		for (embedding, speaker) in embeddings.iter().zip(speakers.iter_mut()) {
+			if let Some(embedding) = embedding {
+				*speaker = closest(embedding, &centroids).map(|(x, _)| x);
+			}
+		}
+
+		centroids = self::centroids(&embeddings, &mut speakers);
+	}
+
+	// Fold the speaker with the least speech into whoever's closest until everyone's said enough
+	while centroids.len() > 1 {
+		let mut time = vec![0.0; centroids.len()];
+
+		for (segment, speaker) in segments.iter().zip(&speakers) {
+			if let Some(speaker) = speaker {
+				time[*speaker] += (segment.end - segment.start) as f32 / 100.0;
+			}
+		}
+
+		let (quietest, least) = time
+			.iter()
+			.copied()
+			.enumerate()
+			.min_by(|x, y| x.1.total_cmp(&y.1))
+			.unwrap();
+
+		if least >= MIN_SPEAKING_TIME {
+			break;
+		}
+
+		let others = centroids
+			.iter()
+			.enumerate()
+			.filter(|(idx, _)| *idx != quietest)
+			.collect::<Vec<_>>();
+
+		for (embedding, speaker) in embeddings.iter().zip(speakers.iter_mut()) {
+			if let (Some(embedding), Some(x)) = (embedding, *speaker) {
+				if x == quietest {
+					*speaker = others
+						.iter()
+						.max_by(|x, y| similarity(embedding, x.1).total_cmp(&similarity(embedding, y.1)))
+						.map(|(idx, _)| *idx);
+				}
+			}
+		}
+
+		centroids = self::centroids(&embeddings, &mut speakers);
+	}
+
+	// Merge speakers that only sound different once scaled
+	loop {
+		let voices = self::centroids(&unscaled, &mut speakers);
+
+		let Some((kept, merged)) = (0..voices.len())
+			.flat_map(|x| (x + 1..voices.len()).map(move |y| (x, y)))
+			.find(|(x, y)| difference(&voices[*x], &voices[*y]) < MIN_DIFFERENCE)
+		else {
+			break;
+		};
+
+		for speaker in speakers.iter_mut().filter(|x| **x == Some(merged)) {
+			*speaker = Some(kept);
+		}
+	}
+
+	if self::centroids(&unscaled, &mut speakers).len() < 2 {
+		return None;
+	}
+
+	// Short and silent segments go with the speaker before, or after at the start
+	let first = speakers.iter().flatten().next().copied();
+	let mut previous = first;
+
+	for speaker in speakers.iter_mut() {
+		match speaker {
+			Some(x) => previous = Some(*x),
+			None => *speaker = previous
+		}
+	}
+
+	let mut order: Vec<usize> = vec![];
+
+	for speaker in speakers.iter().flatten() {
+		if !order.contains(speaker) {
+			order.push(*speaker);
+		}
+	}
+
+	Some(
+		speakers
+			.iter()
+			.map(|x| {
+				let x = x.or(first).unwrap_or_default();
+
+				format!("Speaker {}", order.iter().position(|y| *y == x).unwrap_or_default() + 1)
+			})
+			.collect()
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SEGMENT: usize = 3;
+
+	// A voice-like buzz: harmonics of `pitch` falling off with frequency, wavering in pitch a little, over some hiss
+	fn voice(pitch: f32, seconds: usize, seed: usize) -> Vec<f32> {
+		const VIBRATO: f32 = 5.0;
+		const DEPTH: f32 = 0.02;
+
+		(0..seconds * SAMPLE_RATE as usize)
+			.map(|idx| {
+				let time = idx as f32 / SAMPLE_RATE as f32;
+
+				// Integral of the wavering frequency
+				let wander = DEPTH / (2.0 * PI * VIBRATO) * (2.0 * PI * VIBRATO * time + seed as f32).cos();
+				let phase = 2.0 * PI * pitch * (time - wander);
+
+				let buzz = (1..=10)
+					.map(|harmonic| (phase * harmonic as f32).sin() / harmonic as f32)
+					.sum::<f32>();
+				let hiss = (((idx + seed * 7919) as u64).wrapping_mul(2_654_435_761) % 1000) as f32 / 1000.0 - 0.5;
+
+				buzz * 0.1 + hiss * 0.01
+			})
+			.collect()
+	}
+
+	// Segments of `SEGMENT` seconds, each spoken at the pitch given for it
+	fn conversation(pitches: &[f32]) -> (Vec<f32>, Vec<Utterance>) {
+		let samples = pitches
+			.iter()
+			.enumerate()
+			.flat_map(|(idx, pitch)| voice(*pitch, SEGMENT, idx))
+			.collect();
+
+		let segments = (0..pitches.len())
+			.map(|idx| Utterance {
+				text: format!("Segment {idx}"),
+				start: (idx * SEGMENT * 100) as i64,
+				end: ((idx + 1) * SEGMENT * 100) as i64,
+				probability: 1.0
+			})
+			.collect();
+
+		(samples, segments)
+	}
+
+	#[test]
+	fn tells_two_voices_apart() {
+		let pitches = [120.0, 120.0, 240.0, 120.0, 240.0, 240.0, 120.0, 240.0, 120.0, 240.0];
+		let (samples, segments) = conversation(&pitches);
+
+		let speakers = diarize(&samples, &segments).unwrap();
+
+		let expected = pitches
+			.iter()
+			.map(|x| if *x == 120.0 { "Speaker 1" } else { "Speaker 2" })
+			.collect::<Vec<_>>();
+
+		assert_eq!(speakers, expected);
+	}
+
+	#[test]
+	fn finds_no_speakers_in_a_monologue() {
+		let (samples, segments) = conversation(&[150.0; 10]);
+
+		assert_eq!(diarize(&samples, &segments), None);
+	}
+
+	#[test]
+	fn keeps_a_steady_voice_one_speaker_across_pauses() {
+		let mut samples = vec![];
+		let mut segments = vec![];
+
+		// Speech with two seconds of silence after each segment, which every other segment runs a second into
+		for idx in 0..10 {
+			let start = (samples.len() * 100 / SAMPLE_RATE as usize) as i64;
+
+			samples.extend(voice(150.0, SEGMENT, idx));
+			samples.extend(vec![0.0; 2 * SAMPLE_RATE as usize]);
+
+			segments.push(Utterance {
+				text: format!("Segment {idx}"),
+				start,
+				end: start + (SEGMENT * 100) as i64 + if idx % 2 == 1 { 100 } else { 0 },
+				probability: 1.0
+			});
+		}
+
+		assert_eq!(diarize(&samples, &segments), None);
+	}
+
+	#[test]
+	fn gives_short_segments_the_speaker_before() {
+		let pitches = [120.0, 120.0, 240.0, 120.0, 240.0, 240.0, 120.0, 240.0, 120.0, 240.0];
+		let (samples, mut segments) = conversation(&pitches);
+
+		// Too short to say anything about the voice
+		segments[4].end = segments[4].start + 50;
+
+		let speakers = diarize(&samples, &segments).unwrap();
+
+		assert_eq!(speakers[4], speakers[3]);
+	}
+
+	#[test]
+	fn renumbers_speakers_left_without_segments() {
+		let embeddings = vec![Some(vec![1.0, 0.0]), Some(vec![0.0, 1.0]), None, Some(vec![0.0, 3.0])];
+		let mut speakers = vec![Some(0), Some(2), Some(1), Some(2)];
+
+		let centroids = centroids(&embeddings, &mut speakers);
+
+		assert_eq!(centroids, vec![vec![1.0, 0.0], vec![0.0, 2.0]]);
+		assert_eq!(speakers, vec![Some(0), Some(1), None, Some(1)]);
+	}
+}
@@ -0,0 +1,374 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{vad::SpeechSpan, whisper::Utterance};
+
+// Lines Whisper picked up from video captions and tends to produce over silence or noise. They're real speech often
+// enough that they're only taken out if the segment is doubtful for another reason too.
+static KNOWN_PHRASES: &[&str] = &[
+	"thank you for watching",
+	"thanks for watching",
+	"thank you for watching and see you next time",
+	"please subscribe",
+	"like and subscribe",
+	"don't forget to like and subscribe",
+	"subtitles by the amara.org community",
+	"transcribed by https://otter.ai",
+	"you"
+];
+
+// Segments whose tokens are on average less likely than this are mostly guesses
+const MIN_PROBABILITY: f32 = 0.25;
+// Share of a segment that has to overlap speech
+const MIN_SPEECH_OVERLAP: f32 = 0.1;
+// Most words in an n-gram that's checked for loops
+const MAX_NGRAM: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Reason {
+	/// The same `n` words come `count` times in a row, and all but the first were cut
+	Loop {
+		n: usize,
+		count: usize
+	},
+	/// Same text as the segment before, when that's doubtful or the third time in a row
+	Repeat,
+	LowProbability {
+		probability: f32
+	},
+	/// Falls in a stretch without speech
+	Silence,
+	KnownPhrase,
+	/// Only a marker like [BLANK_AUDIO] or (music)
+	NonSpeech
+}
+
+/// A segment or part of one taken out of the transcript, kept so it can be reviewed
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Removal {
+	pub text: String,
+	pub start: f32,
+	pub end: f32,
+	pub reason: Reason
+}
+
+fn normalise(text: &str) -> String {
+	text.trim()
+		.trim_end_matches(['.', '!', '?', ','])
+		.to_lowercase()
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+// The longest run of an n-gram repeated back to back, as the index of its first word, n and count
+fn find_loop(text: &str) -> Option<(usize, usize, usize)> {
+	let words = text
+		.split_whitespace()
+		.map(|x| x.trim_matches(|x: char| !x.is_alphanumeric()).to_lowercase())
+		.collect::<Vec<_>>();
+
+	let mut longest: Option<(usize, usize, usize)> = None;
+
+	for n in 1..=MAX_NGRAM.min(words.len() / 2) {
+		// Single words are repeated for emphasis now and then, longer phrases rarely are
+		let min_count = if n == 1 { 5 } else { 3 };
+
+		for start in 0..n {
+			let mut count = 1;
+
+			for idx in (start + n..=words.len() - n).step_by(n) {
+				if words[idx..idx + n] == words[idx - n..idx] {
+					count += 1;
+
+					if count >= min_count && longest.is_none_or(|(_, m, c)| n * count > m * c) {
+						longest = Some((idx - n * (count - 1), n, count));
+					}
+				} else {
+					count = 1;
+				}
+			}
+		}
+	}
+
+	longest
+}
+
+// Cuts all but the first of the repeated n-grams out of the segment and returns what was cut, timed by the words cut
+// so they're taken out along with it
+fn trim_loop(segment: &mut Utterance, words: &[Utterance], (first, n, count): (usize, usize, usize)) -> Removal {
+	let text = segment.text.clone();
+
+	// Where each word starts in the text
+	let starts = text
+		.char_indices()
+		.filter(|(idx, x)| !x.is_whitespace() && text[..*idx].chars().next_back().is_none_or(char::is_whitespace))
+		.map(|(idx, _)| idx)
+		.collect::<Vec<_>>();
+
+	let cut = starts[first + n]..starts.get(first + n * count).copied().unwrap_or(text.len());
+
+	// The segment's words spell out its text in order, so where each starts in it says whether it was cut
+	let cut_words = words
+		.iter()
+		.filter(|x| (segment.start..=segment.end).contains(&((x.start + x.end) / 2)))
+		.scan(0, |offset, word| {
+			let start = *offset + word.text.len() - word.text.trim_start().len();
+			*offset += word.text.len();
+
+			Some((start, word))
+		})
+		.filter(|(start, _)| cut.contains(start))
+		.map(|(_, word)| word)
+		.collect::<Vec<_>>();
+
+	let (start, end) = match (cut_words.first(), cut_words.last()) {
+		(Some(first), Some(last)) => (first.start, last.end),
+		// Without words, go by how far into the text the cut is
+		_ => {
+			let at = |idx: usize| segment.start + (segment.end - segment.start) * idx as i64 / text.len() as i64;
+
+			(at(cut.start), at(cut.end))
+		}
+	};
+
+	if cut.end == text.len() {
+		segment.end = start;
+	}
+
+	segment.text = format!("{}{}", &text[..cut.start], &text[cut.end..])
+		.trim_end()
+		.to_owned();
+
+	Removal {
+		text: text[cut].trim().to_owned(),
+		start: start as f32 / 100.0,
+		end: end as f32 / 100.0,
+		reason: Reason::Loop { n, count }
+	}
+}
+
+fn speech_overlap(segment: &Utterance, speech: &[SpeechSpan]) -> f32 {
+	let (start, end) = (segment.start as f32 / 100.0, segment.end as f32 / 100.0);
+
+	if end <= start {
+		return 1.0;
+	}
+
+	speech
+		.iter()
+		.map(|span| (end.min(span.end) - start.max(span.start)).max(0.0))
+		.sum::<f32>()
+		/ (end - start)
+}
+
+fn check(segment: &Utterance, kept: &[Utterance], speech: &[SpeechSpan]) -> Option<Reason> {
+	let text = normalise(&segment.text);
+
+	if text.is_empty() {
+		return None;
+	}
+
+	if (text.starts_with('[') && text.ends_with(']')) || (text.starts_with('(') && text.ends_with(')')) {
+		return Some(Reason::NonSpeech);
+	}
+
+	let is_unlikely = segment.probability < MIN_PROBABILITY;
+	let is_silent = !speech.is_empty() && speech_overlap(segment, speech) < MIN_SPEECH_OVERLAP;
+
+	if KNOWN_PHRASES.contains(&text.as_str()) && (is_unlikely || is_silent) {
+		return Some(Reason::KnownPhrase);
+	}
+
+	// People say "Okay." "Okay." too, but Whisper stuck on a line keeps going
+	let repeats = kept.iter().rev().take_while(|x| normalise(&x.text) == text).count();
+
+	if repeats >= 2 || (repeats == 1 && (is_unlikely || is_silent)) {
+		return Some(Reason::Repeat);
+	}
+
+	if is_unlikely {
+		return Some(Reason::LowProbability {
+			probability: segment.probability
+		});
+	}
+
+	if is_silent {
+		return Some(Reason::Silence);
+	}
+
+	None
+}
+
+/// Takes out segments that look made up and loops within segments, along with their words, and returns what's left
+/// and what was taken out
+pub fn filter(
+	segments: Vec<Utterance>,
+	words: Vec<Utterance>,
+	speech: &[SpeechSpan]
+) -> (Vec<Utterance>, Vec<Utterance>, Vec<Removal>) {
+	let mut kept: Vec<Utterance> = vec![];
+	let mut removals = vec![];
+
+	for mut segment in segments {
+		if let Some(looped) = find_loop(&segment.text) {
+			removals.push(trim_loop(&mut segment, &words, looped));
+		}
+
+		match check(&segment, &kept, speech) {
+			Some(reason) => removals.push(Removal {
+				text: segment.text,
+				start: segment.start as f32 / 100.0,
+				end: segment.end as f32 / 100.0,
+				reason
+			}),
+			None => kept.push(segment)
+		}
+	}
+
+	let words = words
+		.into_iter()
+		.filter(|word| {
+			let middle = (word.start + word.end) as f32 / 200.0;
+
+			!removals
+				.iter()
+				.any(|removal| middle >= removal.start && middle < removal.end)
+		})
+		.collect();
+
+	(kept, words, removals)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn utterance(text: &str, start: i64, end: i64) -> Utterance {
+		Utterance {
+			text: text.into(),
+			start,
+			end,
+			probability: 0.9
+		}
+	}
+
+	// A segment and its tokens, a tenth of a second each
+	fn spoken(tokens: &[&str], start: i64) -> (Utterance, Vec<Utterance>) {
+		let words = tokens
+			.iter()
+			.enumerate()
+			.map(|(idx, x)| utterance(x, start + idx as i64 * 10, start + (idx as i64 + 1) * 10))
+			.collect::<Vec<_>>();
+
+		(utterance(&tokens.concat(), start, words.last().unwrap().end), words)
+	}
+
+	fn texts(utterances: &[Utterance]) -> Vec<&str> {
+		utterances.iter().map(|x| x.text.as_str()).collect()
+	}
+
+	#[test]
+	fn finds_looped_sentence() {
+		assert_eq!(
+			find_loop(" I don't know. I don't know. I don't know. I don't know."),
+			Some((0, 3, 4))
+		);
+	}
+
+	#[test]
+	fn leaves_words_repeated_for_emphasis() {
+		assert_eq!(find_loop(" That is very very important."), None);
+		assert_eq!(find_loop(" No, no, no, that's wrong."), None);
+	}
+
+	#[test]
+	fn cuts_looped_sentence_down_to_first() {
+		let (segment, words) = spoken(
+			&[
+				" I", " don", "'t", " know", ".", " I", " don", "'t", " know", ".", " I", " don", "'t", " know", "."
+			],
+			0
+		);
+
+		let (kept, words, removals) = filter(vec![segment], words, &[]);
+
+		assert_eq!(texts(&kept), [" I don't know."]);
+		assert_eq!(kept[0].end, 50);
+		assert_eq!(texts(&words), [" I", " don", "'t", " know", "."]);
+		assert_eq!(
+			removals,
+			[Removal {
+				text: "I don't know. I don't know.".into(),
+				start: 0.5,
+				end: 1.5,
+				reason: Reason::Loop { n: 3, count: 3 }
+			}]
+		);
+	}
+
+	#[test]
+	fn cuts_loop_at_end_of_segment() {
+		let (segment, words) = spoken(&[" go", " on", " on", " on", " on", " on", " on"], 100);
+
+		let (kept, words, removals) = filter(vec![segment], words, &[]);
+
+		assert_eq!(texts(&kept), [" go on"]);
+		assert_eq!((kept[0].start, kept[0].end), (100, 120));
+		assert_eq!(texts(&words), [" go", " on"]);
+		assert_eq!((removals[0].start, removals[0].end), (1.2, 1.7));
+	}
+
+	#[test]
+	fn keeps_words_lined_up_with_text_after_loop() {
+		let (before, before_words) = spoken(&[" Right", "."], 0);
+		let (segment, words) = spoken(
+			&[
+				" I", " think", " it", " it", " it", " it", " it", " it", " and", " then", " we", "'re", " done"
+			],
+			20
+		);
+
+		let (kept, words, removals) = filter(vec![before, segment], [before_words, words].concat(), &[]);
+
+		assert_eq!(texts(&kept), [" Right.", " I think it and then we're done"]);
+		// The words left spell out the segments left
+		assert_eq!(
+			words.iter().map(|x| x.text.as_str()).collect::<String>(),
+			" Right. I think it and then we're done"
+		);
+		assert_eq!((removals[0].start, removals[0].end), (0.5, 1.0));
+		assert_eq!(kept[1].end, 150);
+	}
+
+	#[test]
+	fn keeps_confident_repeat() {
+		let segments = vec![utterance(" Okay.", 0, 50), utterance(" Okay.", 50, 100)];
+
+		let (kept, _, removals) = filter(segments, vec![], &[SpeechSpan { start: 0.0, end: 1.0 }]);
+
+		assert_eq!(kept.len(), 2);
+		assert!(removals.is_empty());
+	}
+
+	#[test]
+	fn drops_doubtful_or_third_repeat() {
+		let mut doubtful = utterance(" Okay.", 50, 100);
+		doubtful.probability = 0.1;
+
+		let (kept, _, removals) = filter(vec![utterance(" Okay.", 0, 50), doubtful], vec![], &[]);
+
+		assert_eq!(kept.len(), 1);
+		assert_eq!(removals[0].reason, Reason::Repeat);
+
+		let segments = (0..3)
+			.map(|idx| utterance(" Okay.", idx * 50, (idx + 1) * 50))
+			.collect();
+
+		let (kept, _, removals) = filter(segments, vec![], &[]);
+
+		assert_eq!(kept.len(), 2);
+		assert_eq!(removals[0].reason, Reason::Repeat);
+	}
+}
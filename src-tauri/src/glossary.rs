@@ -0,0 +1,305 @@
+use std::{
+	fs,
+	path::{Path, PathBuf}
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tryvial::try_fn;
+
+use crate::pipeline::Segment;
+
+// Terms shorter than this match too many ordinary words
+const MIN_TERM_LENGTH: usize = 4;
+// Shorter terms are only matched up to case and spacing, since so many ordinary words are an edit away from them,
+// like "must" from "Rust"
+const MIN_FUZZY_LENGTH: usize = 6;
+// Edits allowed per character of the term
+const MAX_EDIT_RATE: f32 = 0.25;
+
+/// A word in the transcript replaced with a glossary term
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Correction {
+	pub from: String,
+	pub to: String,
+	pub start: f32,
+	pub end: f32
+}
+
+/// `glossary.txt` in the video's folder, shared by the course's videos
+pub fn glossary_path(video_path: &Path) -> Option<PathBuf> {
+	video_path.parent().map(|x| x.join("glossary.txt"))
+}
+
+/// Terms as a prompt for Whisper, cut short so it doesn't crowd out the context Whisper carries between windows
+pub fn glossary_prompt(glossary: &[String]) -> Option<String> {
+	// Whisper keeps at most half its 448 token context for the prompt
+	const MAX_LENGTH: usize = 600;
+
+	let mut terms = vec![];
+	let mut length = 0;
+
+	for term in glossary {
+		length += term.len() + 2;
+
+		if length > MAX_LENGTH {
+			break;
+		}
+
+		terms.push(term.as_str());
+	}
+
+	(!terms.is_empty()).then(|| format!("Glossary: {}.", terms.join(", ")))
+}
+
+/// The global glossary followed by the course's, one term per line with blank lines and `#` comments left out
+#[try_fn]
+pub fn load_glossary(global: &[String], video_path: &Path) -> Result<Vec<String>> {
+	let mut terms = global.to_vec();
+
+	if let Some(path) = glossary_path(video_path).filter(|x| x.exists()) {
+		terms.extend(
+			fs::read_to_string(path)
+				.context("Couldn't read glossary")?
+				.lines()
+				.map(str::to_owned)
+		);
+	}
+
+	let mut unique: Vec<String> = vec![];
+
+	for term in terms {
+		let term = term.trim();
+
+		if !term.is_empty() && !term.starts_with('#') && !unique.iter().any(|x| x == term) {
+			unique.push(term.to_owned());
+		}
+	}
+
+	unique
+}
+
+// Lowercase letters and digits only, so spacing and punctuation don't count as differences
+fn normalise(text: &str) -> String {
+	text.chars()
+		.filter(|x| x.is_alphanumeric())
+		.flat_map(char::to_lowercase)
+		.collect()
+}
+
+fn levenshtein(x: &[char], y: &[char]) -> usize {
+	let mut previous = (0..=y.len()).collect::<Vec<_>>();
+	let mut current = vec![0; y.len() + 1];
+
+	for (i, a) in x.iter().enumerate() {
+		current[0] = i + 1;
+
+		for (j, b) in y.iter().enumerate() {
+			current[j + 1] = (previous[j] + (a != b) as usize)
+				.min(previous[j + 1] + 1)
+				.min(current[j] + 1);
+		}
+
+		(previous, current) = (current, previous);
+	}
+
+	previous[y.len()]
+}
+
+/// Replaces words that are close to a glossary term with the term, in both the words and the segment they're in, and
+/// returns what was changed. Words are Whisper's tokens, so a word is a run of tokens up to the next leading space.
+pub fn correct(segments: &mut [Segment], words: &mut Vec<Segment>, glossary: &[String]) -> Vec<Correction> {
+	let terms = glossary
+		.iter()
+		.map(|term| {
+			(
+				term.as_str(),
+				normalise(term).chars().collect::<Vec<_>>(),
+				term.split_whitespace().count()
+			)
+		})
+		.filter(|(_, normalised, _)| normalised.len() >= MIN_TERM_LENGTH)
+		.collect::<Vec<_>>();
+
+	// Token indices each word starts at
+	let mut starts = words
+		.iter()
+		.enumerate()
+		.filter(|(idx, word)| *idx == 0 || word.text.starts_with(char::is_whitespace))
+		.map(|(idx, _)| idx)
+		.collect::<Vec<_>>();
+	starts.push(words.len());
+
+	let mut corrections = vec![];
+	// Replacements as (first token, tokens replaced, new token)
+	let mut replacements = vec![];
+
+	let mut word = 0;
+
+	while word + 1 < starts.len() {
+		let mut best: Option<(usize, &str, usize)> = None;
+
+		for (term, normalised, length) in &terms {
+			// Whisper sometimes splits an unfamiliar word in two
+			for span in *length..=*length + 1 {
+				if word + span >= starts.len() {
+					break;
+				}
+
+				let text = words[starts[word]..starts[word + span]]
+					.iter()
+					.map(|x| x.text.as_str())
+					.collect::<String>();
+
+				let candidate = normalise(&text).chars().collect::<Vec<_>>();
+
+				// Already the term, just inflected, like "Dijkstra's"
+				if candidate.len() > normalised.len() && candidate.starts_with(normalised) {
+					continue;
+				}
+
+				let max_distance = if normalised.len() < MIN_FUZZY_LENGTH {
+					0.0
+				} else {
+					normalised.len() as f32 * MAX_EDIT_RATE
+				};
+
+				if candidate.len().abs_diff(normalised.len()) as f32 > max_distance {
+					continue;
+				}
+
+				let distance = levenshtein(&candidate, normalised);
+
+				if distance as f32 <= max_distance && best.is_none_or(|(best, ..)| distance < best) {
+					best = Some((distance, term, span));
+				}
+			}
+		}
+
+		let Some((_, term, span)) = best else {
+			word += 1;
+			continue;
+		};
+
+		let (first, last) = (starts[word], starts[word + span]);
+
+		let original = words[first..last].iter().map(|x| x.text.as_str()).collect::<String>();
+
+		// Keep the spacing before and punctuation after, since they aren't part of the term
+		let leading = &original[..original.len() - original.trim_start().len()];
+		let trailing = &original[original.trim_end_matches(|x: char| !x.is_alphanumeric()).len()..];
+
+		let replacement = format!("{leading}{term}{trailing}");
+
+		let (start, end) = (words[first].start, words[last - 1].end);
+
+		// The segment's text has to stay the same as its words joined up
+		let segment = segments
+			.iter_mut()
+			.find(|x| start >= x.start && start <= x.end && x.text.contains(&original));
+
+		if let (true, Some(segment)) = (replacement != original, segment) {
+			segment.text = segment.text.replacen(&original, &replacement, 1);
+
+			corrections.push(Correction {
+				from: original.trim().to_owned(),
+				to: term.to_owned(),
+				start,
+				end
+			});
+
+			replacements.push((
+				first,
+				last - first,
+				Segment {
+					text: replacement,
+					start,
+					end,
+					probability: words[first..last].iter().filter_map(|x| x.probability).reduce(f32::min),
+					speaker: words[first].speaker.clone()
+				}
+			));
+		}
+
+		word += span;
+	}
+
+	for (first, count, token) in replacements.into_iter().rev() {
+		words.splice(first..first + count, [token]);
+	}
+
+	corrections
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// The sentence as one segment, with a token for each word
+	fn transcript(text: &str) -> (Vec<Segment>, Vec<Segment>) {
+		let segment = |text: &str, start: f32, end: f32| Segment {
+			text: text.to_owned(),
+			start,
+			end,
+			probability: Some(0.9),
+			speaker: None
+		};
+
+		let words = text
+			.split_whitespace()
+			.enumerate()
+			.map(|(idx, x)| segment(&format!(" {x}"), idx as f32, idx as f32 + 1.0))
+			.collect::<Vec<_>>();
+
+		(vec![segment(&format!(" {text}"), 0.0, words.len() as f32)], words)
+	}
+
+	fn corrected(text: &str, glossary: &[&str]) -> (String, Vec<Correction>) {
+		let (mut segments, mut words) = transcript(text);
+		let glossary = glossary.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+		let corrections = correct(&mut segments, &mut words, &glossary);
+
+		assert_eq!(
+			segments[0].text,
+			words.iter().map(|x| x.text.as_str()).collect::<String>()
+		);
+
+		(segments[0].text.trim().to_owned(), corrections)
+	}
+
+	#[test]
+	fn leaves_ordinary_words_near_short_terms() {
+		let sentence = "you must go home if you want to see lava";
+
+		let (text, corrections) = corrected(sentence, &["Rust", "Hume", "Kant", "Java"]);
+
+		assert_eq!(text, sentence);
+		assert!(corrections.is_empty());
+	}
+
+	#[test]
+	fn matches_short_terms_up_to_case_and_spacing() {
+		let (text, corrections) = corrected("we write it in rust and type script", &["Rust", "TypeScript"]);
+
+		assert_eq!(text, "we write it in Rust and TypeScript");
+		assert_eq!(corrections.len(), 2);
+	}
+
+	#[test]
+	fn corrects_long_terms_heard_slightly_wrong() {
+		let (text, corrections) = corrected("then run dikstra on the graph", &["Dijkstra"]);
+
+		assert_eq!(text, "then run Dijkstra on the graph");
+		assert_eq!(corrections[0].from, "dikstra");
+	}
+
+	#[test]
+	fn keeps_inflected_terms() {
+		let (text, corrections) = corrected("by Dijkstra's algorithm", &["Dijkstra"]);
+
+		assert_eq!(text, "by Dijkstra's algorithm");
+		assert!(corrections.is_empty());
+	}
+}
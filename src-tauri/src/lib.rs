@@ -0,0 +1,317 @@
+#![feature(try_blocks)]
+
+pub mod detection;
+pub mod diarize;
+pub mod filter;
+pub mod glossary;
+pub mod models;
+pub mod overlay;
+pub mod pipeline;
+pub mod scanning;
+pub mod subtitles;
+pub mod transcode;
+pub mod vad;
+pub mod whisper;
+
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+	detection::{Detector, RegionOfInterest},
+	models::DEFAULT_MODEL,
+	overlay::OverlayMask,
+	scanning::{ScanMode, ThresholdMode},
+	whisper::DecodingStrategy
+};
+
+static DEFAULT_PROMPT_TEMPLATE: &str = r"The following is an excerpt from a lecture transcript:
+
+##text##
+
+Reformat this excerpt in paragraphed, readable form. Correct any spelling or grammar issues. If parts start with a speaker label like 'Speaker 1:', keep saying who said what. Give only the reformatted text in your response.";
+
+#[derive(Serialize, Deserialize, Clone, Type)]
+pub struct AISettings {
+	use_ai: bool,
+	base_url: String,
+	key: String,
+	model: String,
+	prompt_template: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Type)]
+pub struct DetectionSettings {
+	detector: Detector,
+	// Leave constantly changing areas like webcam insets out of slide-change scoring
+	#[serde(default)]
+	mask_overlays: bool,
+	#[serde(default)]
+	scan_mode: ScanMode,
+	#[serde(default)]
+	threshold: ThresholdMode,
+	// Only used with the adaptive threshold
+	#[serde(default = "default_sensitivity")]
+	sensitivity: f32,
+	// Merge slides that reveal their content bit by bit into one region
+	#[serde(default)]
+	merge_builds: bool
+}
+
+impl Default for DetectionSettings {
+	fn default() -> Self {
+		Self {
+			detector: Detector::default(),
+			mask_overlays: false,
+			scan_mode: ScanMode::default(),
+			threshold: ThresholdMode::default(),
+			sensitivity: default_sensitivity(),
+			merge_builds: false
+		}
+	}
+}
+
+fn default_true() -> bool {
+	true
+}
+
+fn default_sensitivity() -> f32 {
+	1.0
+}
+
+#[derive(Serialize, Deserialize, Clone, Type)]
+pub struct TranscriptionSettings {
+	// Name of a model in the registry
+	model: String,
+	// Code like "de", or detected when unset
+	#[serde(default)]
+	language: Option<String>,
+	// Translate into English rather than transcribing in the spoken language
+	#[serde(default)]
+	translate: bool,
+	#[serde(default)]
+	strategy: DecodingStrategy,
+	// Starting temperature, raised by `temperature_inc` each time a segment fails the thresholds below
+	#[serde(default)]
+	temperature: f32,
+	#[serde(default = "default_temperature_inc")]
+	temperature_inc: f32,
+	#[serde(default = "default_entropy_thold")]
+	entropy_thold: f32,
+	#[serde(default = "default_logprob_thold")]
+	logprob_thold: f32,
+	#[serde(default = "default_no_speech_thold")]
+	no_speech_thold: f32,
+	// Every core when unset
+	#[serde(default)]
+	threads: Option<u32>,
+	// Longest segment in characters, or unlimited when 0
+	#[serde(default)]
+	max_len: u32,
+	// Text to condition the first window on, like the course name and its jargon
+	#[serde(default)]
+	initial_prompt: String,
+	// Skip silence rather than transcribing it
+	#[serde(default = "default_true")]
+	vad: bool,
+	// Take out repeated loops and lines Whisper made up, keeping a record of them
+	#[serde(default = "default_true")]
+	filter_hallucinations: bool,
+	// Names and jargon to prompt Whisper with and correct misheard words to, on top of a course's glossary.txt
+	#[serde(default)]
+	glossary: Vec<String>,
+	// Use a text subtitle track in the video rather than running Whisper
+	#[serde(default)]
+	prefer_embedded_subtitles: bool,
+	// Label which speaker said each segment, for seminars and questions from the audience
+	#[serde(default)]
+	diarize: bool
+}
+
+impl Default for TranscriptionSettings {
+	fn default() -> Self {
+		Self {
+			model: DEFAULT_MODEL.into(),
+			language: None,
+			translate: false,
+			strategy: DecodingStrategy::default(),
+			temperature: 0.0,
+			temperature_inc: default_temperature_inc(),
+			entropy_thold: default_entropy_thold(),
+			logprob_thold: default_logprob_thold(),
+			no_speech_thold: default_no_speech_thold(),
+			threads: None,
+			max_len: 0,
+			initial_prompt: String::new(),
+			vad: true,
+			filter_hallucinations: true,
+			glossary: vec![],
+			prefer_embedded_subtitles: false,
+			diarize: false
+		}
+	}
+}
+
+impl TranscriptionSettings {
+	/// Changes whenever a setting or the course's glossary.txt changes the transcript, so old results can be told apart
+	pub fn cache_key(&self, course_glossary: &str) -> String {
+		// The thread count only changes how fast it goes
+		let settings = Self {
+			threads: None,
+			..self.clone()
+		};
+
+		blake3::hash(
+			format!(
+				"{}\n{course_glossary}",
+				serde_json::to_string(&settings).unwrap_or_default()
+			)
+			.as_bytes()
+		)
+		.to_string()
+	}
+
+	/// Changes whenever a setting Whisper itself runs with does, leaving out what's only done with its output after, so
+	/// changing that doesn't transcribe again. The prompt, which also takes in glossary terms, is up to the caller.
+	pub fn decoding_key(&self) -> String {
+		let settings = (
+			&self.model,
+			&self.language,
+			self.translate,
+			self.strategy,
+			self.temperature,
+			self.temperature_inc,
+			self.entropy_thold,
+			self.logprob_thold,
+			self.no_speech_thold,
+			self.max_len,
+			self.vad
+		);
+
+		blake3::hash(serde_json::to_string(&settings).unwrap_or_default().as_bytes()).to_string()
+	}
+}
+
+fn default_temperature_inc() -> f32 {
+	0.2
+}
+
+fn default_entropy_thold() -> f32 {
+	2.8
+}
+
+fn default_logprob_thold() -> f32 {
+	-1.0
+}
+
+fn default_no_speech_thold() -> f32 {
+	0.6
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Type)]
+pub struct VideoSettings {
+	#[serde(default)]
+	detection: Option<DetectionSettings>,
+	#[serde(default)]
+	roi: RegionOfInterest,
+	#[serde(default)]
+	crop_previews: bool,
+	// Takes precedence over overlay detection
+	#[serde(default)]
+	overlay: OverlayMask,
+	// Index of the audio stream to transcribe, or the best one when unset
+	#[serde(default)]
+	audio_stream: Option<usize>
+}
+
+#[derive(Serialize, Deserialize, Clone, Type)]
+pub struct AppSettings {
+	ai: AISettings,
+	#[serde(default)]
+	detection: DetectionSettings,
+	#[serde(default)]
+	transcription: TranscriptionSettings,
+	// Keyed by video path
+	#[serde(default)]
+	videos: HashMap<String, VideoSettings>
+}
+
+impl AppSettings {
+	fn video(&self, video_path: &Path) -> Option<&VideoSettings> {
+		self.videos.get(video_path.to_string_lossy().as_ref())
+	}
+
+	fn detection_for(&self, video_path: &Path) -> &DetectionSettings {
+		self.video(video_path)
+			.and_then(|x| x.detection.as_ref())
+			.unwrap_or(&self.detection)
+	}
+
+	fn audio_stream_for(&self, video_path: &Path) -> Option<usize> {
+		self.video(video_path).and_then(|x| x.audio_stream)
+	}
+
+	/// Name of the Whisper model to transcribe with
+	pub fn model(&self) -> &str {
+		&self.transcription.model
+	}
+}
+
+impl Default for AppSettings {
+	fn default() -> Self {
+		Self {
+			ai: AISettings {
+				use_ai: false,
+				base_url: "https://api.mistral.ai/v1".into(),
+				key: "".into(),
+				model: "mistral-large-latest".into(),
+				prompt_template: DEFAULT_PROMPT_TEMPLATE.into()
+			},
+			detection: DetectionSettings::default(),
+			transcription: TranscriptionSettings::default(),
+			videos: HashMap::new()
+		}
+	}
+}
+
+// Proportion of same pixels between two frames
+// fn similarity(x: &[u8], y: &[u8]) -> f64 {
+// 	let mut same = 0;
+
+// 	for i in 0..x.len() {
+// 		if x[i] == y[i] {
+// 			same += 1;
+// 		}
+// 	}
+
+// 	same as f64 / x.len() as f64
+// }
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", content = "data")]
+#[serde(rename_all = "camelCase")]
+pub enum Progress {
+	Transcoding(BasicProgress),
+	Downloading(ExtendedProgress),
+	Transcribing(ExtendedProgress),
+	Processing(ExtendedProgress),
+	GatheringPreviews(ExtendedProgress),
+	Summarising(ExtendedProgress)
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum BasicProgress {
+	Started,
+	Done
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", content = "data")]
+#[serde(rename_all = "camelCase")]
+pub enum ExtendedProgress {
+	Preparing,
+	Progress(f32, f32),
+	Done
+}
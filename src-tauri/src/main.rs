@@ -1,61 +1,31 @@
-#![feature(try_blocks)]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 
 mod commands;
 mod processing;
-mod transcode;
-mod whisper;
 
-use std::{
-	fs,
-	sync::{
-		Arc, Mutex,
-		mpsc::{Sender, channel}
-	},
-	time::Instant
-};
+use std::{fs, sync::Arc};
 
+use app_lib::{AppSettings, whisper::ModelCache};
 use arc_swap::ArcSwap;
-use serde::{Deserialize, Serialize};
 use serde_json::{from_slice, to_string};
 use specta::{
-	Type, collect_types,
+	collect_types,
 	ts::{BigIntExportBehavior, ExportConfiguration}
 };
-use tauri::{Manager, async_runtime};
+use tauri::Manager;
 use tauri_specta::ts;
 
 use crate::{
-	commands::{rs_get_settings, rs_save_current_time, rs_save_settings},
+	commands::{
+		rs_get_settings, rs_import_model, rs_list_audio_streams, rs_list_models, rs_save_current_time, rs_save_settings
+	},
 	processing::rs_process_regions
 };
 
 // #[global_allocator]
 // static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
-static WHISPER_PROGRESS_SENDER: Mutex<Option<Sender<i32>>> = Mutex::new(None);
-
-static DEFAULT_PROMPT_TEMPLATE: &str = r"The following is an excerpt from a lecture transcript:
-
-##text##
-
-Reformat this excerpt in paragraphed, readable form. Correct any spelling or grammar issues. Give only the reformatted text in your response.";
-
-#[derive(Serialize, Deserialize, Clone, Type)]
-pub struct AISettings {
-	use_ai: bool,
-	base_url: String,
-	key: String,
-	model: String,
-	prompt_template: String
-}
-
-#[derive(Serialize, Deserialize, Clone, Type)]
-pub struct AppSettings {
-	ai: AISettings
-}
-
 fn main() {
 	#[cfg(debug_assertions)]
 	ts::export_with_cfg(
@@ -63,7 +33,10 @@ fn main() {
 			rs_process_regions,
 			rs_save_current_time,
 			rs_get_settings,
-			rs_save_settings
+			rs_save_settings,
+			rs_list_models,
+			rs_import_model,
+			rs_list_audio_streams
 		]
 		.unwrap(),
 		ExportConfiguration::new().bigint(BigIntExportBehavior::Number),
@@ -76,13 +49,12 @@ fn main() {
 			rs_process_regions,
 			rs_save_current_time,
 			rs_get_settings,
-			rs_save_settings
+			rs_save_settings,
+			rs_list_models,
+			rs_import_model,
+			rs_list_audio_streams
 		])
 		.setup(|app| {
-			let (tx, rx) = channel();
-
-			let _ = WHISPER_PROGRESS_SENDER.lock().unwrap().insert(tx);
-
 			if !app
 				.path_resolver()
 				.app_data_dir()
@@ -97,16 +69,7 @@ fn main() {
 
 				fs::write(
 					app.path_resolver().app_data_dir().unwrap().join("settings.json"),
-					to_string(&AppSettings {
-						ai: AISettings {
-							use_ai: false,
-							base_url: "https://api.mistral.ai/v1".into(),
-							key: "".into(),
-							model: "mistral-large-latest".into(),
-							prompt_template: DEFAULT_PROMPT_TEMPLATE.into()
-						}
-					})
-					.unwrap()
+					to_string(&AppSettings::default()).unwrap()
 				)
 				.unwrap();
 			}
@@ -121,67 +84,10 @@ fn main() {
 				.into()
 			);
 
-			let app = app.handle();
-
-			async_runtime::spawn(async move {
-				let mut first_time = None;
-
-				while let Ok(progress) = rx.recv() {
-					app.emit_all(
-						"progress",
-						Progress::Transcribing(ExtendedProgress::Progress(
-							(progress as f32 / 100.0).clamp(0.0, 1.0),
-							(Instant::now() - *first_time.get_or_insert(Instant::now())).as_secs_f32()
-								/ progress as f32 * (100 - progress) as f32
-						))
-					)
-					.unwrap();
-				}
-			});
+			app.manage(ModelCache::default());
 
 			Ok(())
 		})
 		.run(tauri::generate_context!())
 		.expect("error while running tauri application");
 }
-
-// Proportion of same pixels between two frames
-// fn similarity(x: &[u8], y: &[u8]) -> f64 {
-// 	let mut same = 0;
-
-// 	for i in 0..x.len() {
-// 		if x[i] == y[i] {
-// 			same += 1;
-// 		}
-// 	}
-
-// 	same as f64 / x.len() as f64
-// }
-
-#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
-#[serde(tag = "type", content = "data")]
-#[serde(rename_all = "camelCase")]
-pub enum Progress {
-	Transcoding(BasicProgress),
-	Downloading(ExtendedProgress),
-	Transcribing(ExtendedProgress),
-	Processing(ExtendedProgress),
-	GatheringPreviews(ExtendedProgress),
-	Summarising(ExtendedProgress)
-}
-
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
-#[serde(rename_all = "camelCase")]
-pub enum BasicProgress {
-	Started,
-	Done
-}
-
-#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
-#[serde(tag = "type", content = "data")]
-#[serde(rename_all = "camelCase")]
-pub enum ExtendedProgress {
-	Preparing,
-	Progress(f32, f32),
-	Done
-}
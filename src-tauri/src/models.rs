@@ -0,0 +1,382 @@
+use std::{
+	collections::HashMap,
+	fs::{self, File, OpenOptions},
+	io::{self, Read, Write},
+	iter,
+	path::{Path, PathBuf},
+	time::Instant
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use fn_error_context::context;
+use futures::StreamExt;
+use itertools::Itertools;
+use reqwest::{header::RANGE, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_string};
+use sha1::{Digest, Sha1};
+use specta::Type;
+use tauri::async_runtime;
+use tryvial::try_fn;
+
+use crate::{pipeline::Reporter, ExtendedProgress, Progress};
+
+/// A Whisper model that can be downloaded
+pub struct Model {
+	pub name: &'static str,
+	pub url: &'static str,
+	// SHA-1 of the file, as published in whisper.cpp's list of models. These are the only hashes published for the
+	// models, and one worked out here from a download would only show the download matches itself, so they're used
+	// instead of BLAKE3.
+	pub hash: &'static str,
+	// Approximate, for showing before downloading
+	pub size_mb: u32,
+	pub multilingual: bool
+}
+
+macro_rules! model {
+	($name:literal, $size_mb:literal, $multilingual:literal, $hash:literal) => {
+		Model {
+			name: $name,
+			url: concat!(
+				"https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-",
+				$name,
+				".bin?download=true"
+			),
+			hash: $hash,
+			size_mb: $size_mb,
+			multilingual: $multilingual
+		}
+	};
+}
+
+pub static MODELS: &[Model] = &[
+	model!("tiny.en", 75, false, "c78c86eb1a8faa21b369bcd33207cc90d64ae9df"),
+	model!("tiny", 75, true, "bd577a113a864445d4c299885e0cb97d4ba92b5f"),
+	model!("base.en", 142, false, "137c40403d78fd54d454da0f9bd998f78703390c"),
+	model!("base", 142, true, "465707469ff3a37a2b9b8d8f89f2f99de7299dac"),
+	model!("small.en", 466, false, "db8a495a91d927739e50b3fc1cc4c6b8f6c2d022"),
+	model!("small", 466, true, "55356645c2b361a969dfd0ef2c5a50d530afd8d5"),
+	model!("medium.en", 1500, false, "8c30f0e44ce9560643ebd10bbe50cd20eafd3723"),
+	model!("medium", 1500, true, "fd9727b6e1217c2f614f9b698455c4ffd82463b4"),
+	model!("large-v3", 2900, true, "ad82bf6a9043ceed055076d0fd39f5f186ff8062"),
+	model!("large-v3-turbo", 1500, true, "4af2b29d7ec73d781377bfd1758ca957a807e941")
+];
+
+pub static DEFAULT_MODEL: &str = "medium.en";
+
+// ggml files start with this, followed by the model's hyperparameters
+const GGML_MAGIC: u32 = 0x6767_6d6c;
+// English-only models have one token fewer
+const MULTILINGUAL_VOCAB: u32 = 51865;
+
+/// A model as shown in the settings
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Type)]
+pub struct ModelEntry {
+	pub name: String,
+	pub size_mb: u32,
+	pub multilingual: bool,
+	pub installed: bool,
+	// Imported by the user rather than from the registry
+	pub custom: bool
+}
+
+pub fn find(name: &str) -> Option<&'static Model> {
+	MODELS.iter().find(|x| x.name == name)
+}
+
+fn models_path(data_path: &Path) -> PathBuf {
+	data_path.join("models")
+}
+
+pub fn model_path(data_path: &Path, name: &str) -> PathBuf {
+	models_path(data_path).join(format!("ggml-{name}.bin"))
+}
+
+// Hashes of installed models, by name
+#[try_fn]
+fn read_index(data_path: &Path) -> Result<HashMap<String, String>> {
+	let path = models_path(data_path).join("models.json");
+
+	if path.exists() {
+		from_slice(&fs::read(path).context("Couldn't read model index")?).context("Couldn't deserialise model index")?
+	} else {
+		HashMap::new()
+	}
+}
+
+#[try_fn]
+fn write_index(data_path: &Path, index: &HashMap<String, String>) -> Result<()> {
+	fs::write(models_path(data_path).join("models.json"), to_string(index)?).context("Couldn't write model index")?;
+}
+
+#[try_fn]
+fn hash_file(path: &Path) -> Result<String> {
+	let mut hasher = Sha1::new();
+
+	io::copy(&mut File::open(path).context("Couldn't open model")?, &mut hasher).context("Couldn't read model")?;
+
+	format!("{:x}", hasher.finalize())
+}
+
+// Checks the file is a Whisper ggml model and returns whether it's multilingual
+#[try_fn]
+fn read_header(path: &Path) -> Result<bool> {
+	let mut header = [0u8; 48];
+
+	File::open(path)
+		.context("Couldn't open model")?
+		.read_exact(&mut header)
+		.context("File is too short to be a model")?;
+
+	let field = |idx: usize| u32::from_le_bytes(header[idx * 4..idx * 4 + 4].try_into().unwrap());
+
+	ensure!(field(0) == GGML_MAGIC, "File isn't a ggml model");
+
+	// Vocabulary size through to the number of mel bands, all of which have to be set
+	ensure!(
+		(1..=10).all(|idx| field(idx) as i32 > 0),
+		"Model has invalid hyperparameters"
+	);
+
+	field(1) >= MULTILINGUAL_VOCAB
+}
+
+#[try_fn]
+pub fn list_models(data_path: &Path) -> Result<Vec<ModelEntry>> {
+	let mut models = MODELS
+		.iter()
+		.map(|model| ModelEntry {
+			name: model.name.into(),
+			size_mb: model.size_mb,
+			multilingual: model.multilingual,
+			installed: model_path(data_path, model.name).exists(),
+			custom: false
+		})
+		.collect::<Vec<_>>();
+
+	for name in read_index(data_path)?
+		.into_keys()
+		.filter(|x| find(x).is_none())
+		.sorted()
+	{
+		let path = model_path(data_path, &name);
+
+		// A missing or corrupt model is left out rather than hiding all the others
+		let (Ok(metadata), Ok(multilingual)) = (fs::metadata(&path), read_header(&path)) else {
+			continue;
+		};
+
+		models.push(ModelEntry {
+			size_mb: (metadata.len() / 1024 / 1024) as u32,
+			multilingual,
+			installed: true,
+			custom: true,
+			name
+		});
+	}
+
+	models
+}
+
+/// Copies a model the user already has into the models folder, or links it if possible. It's stored under its
+/// registry name if its hash matches one, and as a custom model otherwise, numbered if a different model already has
+/// that name. Returns the name to select it by.
+#[try_fn]
+#[context("Couldn't import model")]
+pub fn import_model(data_path: &Path, source: &Path) -> Result<String> {
+	read_header(source)?;
+
+	let hash = hash_file(source)?;
+
+	let mut index = read_index(data_path)?;
+
+	let name = match MODELS.iter().find(|x| x.hash == hash) {
+		Some(model) => model.name.to_owned(),
+		None => {
+			let stem = source.file_stem().context("Model has no file name")?.to_string_lossy();
+
+			let name = format!(
+				"custom-{}",
+				stem.trim_start_matches("ggml-")
+					.chars()
+					.map(|x| if x.is_ascii_alphanumeric() || x == '.' || x == '-' {
+						x
+					} else {
+						'_'
+					})
+					.collect::<String>()
+			);
+
+			iter::once(name.clone())
+				.chain((2..).map(|idx| format!("{name}-{idx}")))
+				.find(|x| index.get(x).is_none_or(|x| *x == hash))
+				.unwrap()
+		}
+	};
+
+	let path = model_path(data_path, &name);
+
+	// Importing the same model again
+	if index.get(&name) == Some(&hash) && path.exists() {
+		return Ok(name);
+	}
+
+	fs::create_dir_all(models_path(data_path)).context("Couldn't ensure models folder")?;
+
+	// Land it next to the final path first so a failed copy never leaves a partial model under the real name
+	let temp_path = path.with_extension("import");
+
+	let _ = fs::remove_file(&temp_path);
+
+	if fs::hard_link(source, &temp_path).is_err() {
+		fs::copy(source, &temp_path).context("Couldn't copy model")?;
+	}
+
+	fs::rename(&temp_path, &path).context("Couldn't move model")?;
+
+	index.insert(name.clone(), hash);
+	write_index(data_path, &index)?;
+
+	name
+}
+
+/// Path to the named model, downloading it first if it isn't installed. Models only get into the models folder once
+/// their hash is checked, so one listed in the index with the right hash is trusted without hashing it again.
+#[try_fn]
+#[context("Couldn't get model {name}")]
+pub fn ensure_model(data_path: &Path, name: &str, reporter: &dyn Reporter) -> Result<PathBuf> {
+	let path = model_path(data_path, name);
+
+	fs::create_dir_all(models_path(data_path)).context("Couldn't ensure models folder")?;
+
+	let mut index = read_index(data_path)?;
+
+	let Some(model) = find(name) else {
+		// Imported models have nowhere to be downloaded from
+		ensure!(
+			index.contains_key(name) && path.exists(),
+			"Model isn't installed, try importing it again"
+		);
+
+		return Ok(path);
+	};
+
+	// Older versions kept a single medium.en model here. It's moved over once and then checked like any other, so one
+	// that doesn't match is downloaded again rather than hashed on every start.
+	let legacy_path = data_path.join("model.bin");
+	if name == "medium.en" && !path.exists() && legacy_path.exists() {
+		fs::rename(&legacy_path, &path).context("Couldn't move model")?;
+	}
+
+	if path.exists() {
+		if index.get(name).is_some_and(|x| x == model.hash) {
+			return Ok(path);
+		}
+
+		// Indexed by an older version with another kind of hash, or not at all
+		if hash_file(&path).ok().as_deref() == Some(model.hash) {
+			index.insert(name.into(), model.hash.into());
+			write_index(data_path, &index)?;
+
+			return Ok(path);
+		}
+	}
+
+	reporter.report(Progress::Downloading(ExtendedProgress::Preparing))?;
+
+	async_runtime::block_on(download(model.url, &path, model.hash, reporter))?;
+
+	index.insert(name.into(), model.hash.into());
+	write_index(data_path, &index)?;
+
+	reporter.report(Progress::Downloading(ExtendedProgress::Done))?;
+
+	path
+}
+
+/// Where a download to `path` goes until it's verified
+pub fn part_path(path: &Path) -> PathBuf {
+	let mut x = path.as_os_str().to_owned();
+	x.push(".part");
+	x.into()
+}
+
+/// Downloads `url` to `path`, hashing it as it arrives. Data goes to a `.part` file first, which a later attempt picks
+/// up from with a range request if this one is interrupted, and is only moved into place once it matches the SHA-1
+/// `expected`.
+#[try_fn]
+pub async fn download(url: &str, path: &Path, expected: &str, reporter: &dyn Reporter) -> Result<()> {
+	let part_path = part_path(path);
+
+	let mut hasher = Sha1::new();
+
+	let mut offset = if part_path.exists() {
+		io::copy(
+			&mut File::open(&part_path).context("Couldn't open partial download")?,
+			&mut hasher
+		)
+		.context("Couldn't read partial download")?
+	} else {
+		0
+	};
+
+	let mut request = reqwest::Client::new().get(url);
+
+	if offset > 0 {
+		request = request.header(RANGE, format!("bytes={offset}-"));
+	}
+
+	let res = request.send().await?;
+
+	// An earlier attempt got the whole file but didn't get to move it into place
+	let complete = offset > 0 && res.status() == StatusCode::RANGE_NOT_SATISFIABLE;
+
+	if !complete {
+		let res = res.error_for_status()?;
+
+		// Servers that ignore the range send the whole file again
+		let resumed = offset > 0 && res.status() == StatusCode::PARTIAL_CONTENT;
+
+		let mut file = if resumed {
+			OpenOptions::new().append(true).open(&part_path)
+		} else {
+			hasher.reset();
+			offset = 0;
+
+			File::create(&part_path)
+		}
+		.context("Couldn't open model file")?;
+
+		let total_size = offset + res.content_length().context("Couldn't get content length")?;
+
+		let start_time = Instant::now();
+
+		let mut downloaded = offset;
+		let mut stream = res.bytes_stream();
+
+		while let Some(item) = stream.next().await {
+			let chunk = item.context("Error while downloading file")?;
+
+			file.write_all(&chunk).context("Error while writing to file")?;
+			hasher.update(&chunk);
+
+			downloaded = total_size.min(downloaded + (chunk.len() as u64));
+
+			reporter.report(Progress::Downloading(ExtendedProgress::Progress(
+				downloaded as f32 / total_size as f32,
+				(Instant::now() - start_time).as_secs_f32() / (downloaded - offset) as f32
+					* (total_size as f32 - downloaded as f32)
+			)))?;
+		}
+
+		file.sync_all().context("Error while writing to file")?;
+	}
+
+	if format!("{:x}", hasher.finalize()) != expected {
+		fs::remove_file(&part_path).context("Couldn't remove corrupt download")?;
+		bail!("Downloaded model doesn't match its hash");
+	}
+
+	fs::rename(&part_path, path).context("Couldn't move model into place")?;
+}
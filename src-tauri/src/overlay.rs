@@ -0,0 +1,161 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tryvial::try_fn;
+use video_rs::{decode::DecoderBuilder, resize::Resize};
+
+use crate::detection::{luma_grid, Rect};
+
+const GRID_WIDTH: usize = 64;
+const GRID_HEIGHT: usize = 36;
+
+const WINDOWS: usize = 12;
+const FRAMES_PER_WINDOW: usize = 8;
+const FRAME_STEP: f32 = 0.5;
+
+// Luma variance above which a cell counts as moving within a window
+const VARIANCE_THRESHOLD: f32 = 2.0;
+// Share of windows a cell has to be moving in to count as an overlay rather than a slide change
+const PERSISTENCE: f32 = 0.6;
+
+/// What's masked as a video's overlay, so a wrong detection can be corrected
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum OverlayMask {
+	/// Detected if masking overlays is turned on
+	#[default]
+	Detect,
+	/// Nothing is masked, even if masking overlays is turned on
+	Off,
+	/// This area is masked without detecting anything
+	Manual(Rect)
+}
+
+/// Finds an area of the frame that changes constantly, like a webcam inset, by sampling short windows across the
+/// video and looking for cells whose brightness varies in most of them
+#[try_fn]
+pub fn detect_overlay(video_path: &Path) -> Result<Option<Rect>> {
+	let mut video = DecoderBuilder::new(video_path.to_owned())
+		.with_resize(Resize::Fit(320, 180))
+		.build()
+		.context("Couldn't open video")?;
+
+	let duration = video.duration()?.as_secs();
+
+	let mut moving = vec![0usize; GRID_WIDTH * GRID_HEIGHT];
+	let mut windows = 0;
+
+	for window in 0..WINDOWS {
+		let mut target = duration * window as f32 / WINDOWS as f32;
+
+		if video.seek((target * 1000.0) as i64).is_err() {
+			continue;
+		}
+
+		let mut grids = vec![];
+
+		while grids.len() < FRAMES_PER_WINDOW {
+			let Ok((time, frame)) = video.decode() else {
+				break;
+			};
+
+			if time.as_secs() >= target {
+				grids.push(luma_grid(&frame, GRID_WIDTH, GRID_HEIGHT));
+				target += FRAME_STEP;
+			}
+		}
+
+		if grids.len() < FRAMES_PER_WINDOW / 2 {
+			continue;
+		}
+
+		windows += 1;
+
+		for (cell, moving) in moving.iter_mut().enumerate() {
+			let mean = grids.iter().map(|grid| grid[cell]).sum::<f32>() / grids.len() as f32;
+			let variance = grids.iter().map(|grid| (grid[cell] - mean).powi(2)).sum::<f32>() / grids.len() as f32;
+
+			if variance > VARIANCE_THRESHOLD {
+				*moving += 1;
+			}
+		}
+	}
+
+	if windows < 3 {
+		return Ok(None);
+	}
+
+	let persistent = moving
+		.into_iter()
+		.map(|count| count as f32 >= windows as f32 * PERSISTENCE)
+		.collect::<Vec<_>>();
+
+	let Some((left, top, right, bottom)) = largest_component(&persistent) else {
+		return Ok(None);
+	};
+
+	let area = ((right - left + 1) * (bottom - top + 1)) as f32 / (GRID_WIDTH * GRID_HEIGHT) as f32;
+
+	// Tiny areas are a cursor or noise, huge ones are video content rather than an inset
+	if !(0.005..=0.5).contains(&area) {
+		return Ok(None);
+	}
+
+	// Pad by a cell, since the edges of a moving subject aren't always caught
+	let (left, top) = (left.saturating_sub(1), top.saturating_sub(1));
+	let (right, bottom) = ((right + 1).min(GRID_WIDTH - 1), (bottom + 1).min(GRID_HEIGHT - 1));
+
+	Some(Rect {
+		x: left as f32 / GRID_WIDTH as f32,
+		y: top as f32 / GRID_HEIGHT as f32,
+		width: (right - left + 1) as f32 / GRID_WIDTH as f32,
+		height: (bottom - top + 1) as f32 / GRID_HEIGHT as f32
+	})
+}
+
+// Bounding box (left, top, right, bottom) of the largest 4-connected group of set cells
+fn largest_component(cells: &[bool]) -> Option<(usize, usize, usize, usize)> {
+	let mut seen = vec![false; cells.len()];
+	let mut largest: Option<(usize, (usize, usize, usize, usize))> = None;
+
+	for start in 0..cells.len() {
+		if !cells[start] || seen[start] {
+			continue;
+		}
+
+		seen[start] = true;
+
+		let mut stack = vec![start];
+		let mut size = 0;
+		let mut bounds = (usize::MAX, usize::MAX, 0, 0);
+
+		while let Some(cell) = stack.pop() {
+			let (x, y) = (cell % GRID_WIDTH, cell / GRID_WIDTH);
+
+			size += 1;
+			bounds = (bounds.0.min(x), bounds.1.min(y), bounds.2.max(x), bounds.3.max(y));
+
+			let neighbours = [
+				(x > 0).then(|| cell - 1),
+				(x + 1 < GRID_WIDTH).then(|| cell + 1),
+				(y > 0).then(|| cell - GRID_WIDTH),
+				(y + 1 < GRID_HEIGHT).then(|| cell + GRID_WIDTH)
+			];
+
+			for neighbour in neighbours.into_iter().flatten() {
+				if cells[neighbour] && !seen[neighbour] {
+					seen[neighbour] = true;
+					stack.push(neighbour);
+				}
+			}
+		}
+
+		if largest.is_none_or(|(largest, _)| size > largest) {
+			largest = Some((size, bounds));
+		}
+	}
+
+	largest.map(|(_, bounds)| bounds)
+}
@@ -0,0 +1,655 @@
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicU32, Ordering},
+		Arc, Mutex
+	},
+	time::{Duration, Instant}
+};
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use image::{ImageBuffer, Rgb};
+use itertools::Itertools;
+use openai_dive::v1::{
+	api::Client,
+	resources::chat::{ChatCompletionParametersBuilder, ChatMessage, ChatMessageContent}
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, from_value, to_string, Value};
+use tryvial::try_fn;
+use video_rs::{decode::Decoder, Frame};
+use whisper_rs::{get_lang_id, get_lang_str_full, WhisperContextParameters};
+
+use crate::{
+	detection::{is_build_step, Fingerprint},
+	diarize::diarize,
+	filter::filter,
+	glossary::{correct, glossary_path, load_glossary},
+	models::ensure_model,
+	overlay::{detect_overlay, OverlayMask},
+	scanning::{debounce, ScanMode, Scanner, ThresholdMode},
+	subtitles::read_subtitles,
+	transcode::decode_audio,
+	whisper::{transcribe, ModelCache, Utterance},
+	AppSettings, BasicProgress, ExtendedProgress, Progress
+};
+
+/// Receives progress updates as the pipeline runs
+pub trait Reporter: Send + Sync {
+	fn report(&self, progress: Progress) -> Result<()>;
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Region {
+	pub segments: Vec<Segment>,
+	pub words: Option<Vec<Segment>>,
+	pub start: f32,
+	pub end: f32,
+	pub summary: String,
+	// Start times of each step of a slide that's built up bit by bit
+	#[serde(default)]
+	pub builds: Option<Vec<f32>>,
+	// Shared by regions showing the same slide, and names the preview image
+	pub slide_id: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Segment {
+	pub text: String,
+	pub start: f32,
+	pub end: f32,
+	// How sure Whisper was, averaged over tokens for whole segments
+	#[serde(default)]
+	pub probability: Option<f32>,
+	// Like "Speaker 1", if speakers were told apart
+	#[serde(default)]
+	pub speaker: Option<String>
+}
+
+/// Records how a video's regions were made
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+	pub detector: String,
+	pub threshold: f32,
+	// Spoken language code, if known
+	#[serde(default)]
+	pub language: Option<String>,
+	// Whether the transcript was translated into English
+	#[serde(default)]
+	pub translated: bool,
+	// Key of the transcription settings and glossary used, or unset when the transcript came from a JSON file
+	#[serde(default)]
+	pub transcription_key: Option<String>,
+	// The video had no audio, so only slides were found
+	#[serde(default)]
+	pub no_audio: bool
+}
+
+/// Folder a video's results go in, named after the hash of its contents so renamed or moved videos are recognised
+#[try_fn]
+#[context("Couldn't get output folder")]
+pub fn output_folder(videos_path: &Path, video_path: &Path) -> Result<PathBuf> {
+	videos_path.join(
+		blake3::Hasher::new()
+			.update_rayon(&fs::read(video_path).context("Couldn't read video")?)
+			.finalize()
+			.to_string()
+	)
+}
+
+/// Changes whenever the transcription settings, the audio stream chosen or the course's glossary do
+pub fn transcription_key(video_path: &Path, settings: &AppSettings) -> String {
+	let glossary = glossary_path(video_path)
+		.and_then(|x| fs::read_to_string(x).ok())
+		.unwrap_or_default();
+
+	blake3::hash(
+		format!(
+			"{}\n{:?}",
+			settings.transcription.cache_key(&glossary),
+			settings.audio_stream_for(video_path)
+		)
+		.as_bytes()
+	)
+	.to_string()
+}
+
+// The segments' text joined up, with a paragraph for each turn if speakers were told apart
+fn transcript_text(segments: &[Segment]) -> String {
+	segments
+		.iter()
+		.chunk_by(|x| &x.speaker)
+		.into_iter()
+		.map(|(speaker, turn)| {
+			let text = turn.map(|Segment { text, .. }| text.as_str()).join(" ");
+
+			match speaker {
+				Some(speaker) => format!("{speaker}: {}", text.trim()),
+				None => text
+			}
+		})
+		.join("\n\n")
+}
+
+/// Whether the video has results that are still current, meaning they weren't transcribed with different settings
+pub fn is_processed(output_path: &Path, video_path: &Path, settings: &AppSettings) -> bool {
+	output_path.join("regions.json").exists()
+		&& fs::read(output_path.join("metadata.json"))
+			.ok()
+			.and_then(|x| from_slice::<Metadata>(&x).ok())
+			.and_then(|x| x.transcription_key)
+			.is_none_or(|key| key == transcription_key(video_path, settings))
+}
+
+/// Transcribes the video, splits it into regions by slide, saves previews and summarises each region, writing
+/// everything to `output_path`. Whisper models are kept in `data_path`, and stay loaded in `models` between videos.
+#[try_fn]
+#[context("Couldn't process regions")]
+pub async fn process(
+	video_path: &Path,
+	output_path: &Path,
+	data_path: &Path,
+	models: &ModelCache,
+	settings: &AppSettings,
+	reporter: Arc<dyn Reporter>
+) -> Result<()> {
+	let detection = settings.detection_for(video_path);
+
+	let detector = detection.detector.build();
+
+	let video_settings = settings.video(video_path).cloned().unwrap_or_default();
+
+	let glossary = load_glossary(&settings.transcription.glossary, video_path)?;
+
+	fs::create_dir_all(output_path).context("Couldn't ensure output folder")?;
+
+	let json_path = {
+		let mut x = video_path.to_owned();
+		x.pop();
+		x.join(format!("{}.json", video_path.file_stem().unwrap().to_str().unwrap()))
+	};
+
+	let (a, b) = rayon::join(
+		|| {
+			anyhow::Ok({
+				let subtitles = if !json_path.exists() && settings.transcription.prefer_embedded_subtitles {
+					read_subtitles(video_path).context("Couldn't read subtitles")?
+				} else {
+					None
+				};
+
+				if json_path.exists() {
+					reporter.report(Progress::Transcribing(ExtendedProgress::Preparing))?;
+
+					let transcript =
+						from_slice::<Value>(&fs::read(&json_path).context("Couldn't read transcription JSON")?)
+							.context("Couldn't deserialise transcription JSON")?;
+
+					let segments = from_value::<Vec<Segment>>(
+						transcript.get("segments").context("Couldn't get segments")?.to_owned()
+					)
+					.context("Couldn't deserialise segments")?;
+
+					reporter.report(Progress::Transcribing(ExtendedProgress::Done))?;
+
+					(
+						segments,
+						None,
+						// Whisper's own JSON output says which language it heard
+						transcript.get("language").and_then(Value::as_str).map(str::to_owned),
+						false,
+						false
+					)
+				} else if let Some(segments) = subtitles {
+					reporter.report(Progress::Transcribing(ExtendedProgress::Done))?;
+
+					(segments, None, None, false, false)
+				} else {
+					reporter.report(Progress::Transcoding(BasicProgress::Started))?;
+					let samples = decode_audio(video_path, settings.audio_stream_for(video_path))
+						.context("Couldn't decode audio")?;
+					reporter.report(Progress::Transcoding(BasicProgress::Done))?;
+
+					// Nothing to transcribe, but the slides are still worth having
+					let Some(samples) = samples else {
+						reporter.report(Progress::Transcribing(ExtendedProgress::Done))?;
+
+						return Ok((vec![], None, None, false, true));
+					};
+
+					let transcript = transcribe(
+						|| {
+							let model_path = ensure_model(data_path, &settings.transcription.model, reporter.as_ref())?;
+
+							reporter.report(Progress::Transcribing(ExtendedProgress::Preparing))?;
+
+							models.get(&model_path, WhisperContextParameters::default())
+						},
+						&samples,
+						&settings.transcription,
+						&glossary,
+						&output_path.join("checkpoint.json"),
+						&format!("{:?}", settings.audio_stream_for(video_path)),
+						{
+							let reporter = reporter.clone();
+							let mut first = None;
+
+							move |progress| {
+								let progress = progress.clamp(0, 100);
+								// A resumed run starts part of the way through
+								let (first_time, first_progress) = *first.get_or_insert((Instant::now(), progress));
+
+								let _ = reporter.report(Progress::Transcribing(ExtendedProgress::Progress(
+									progress as f32 / 100.0,
+									(Instant::now() - first_time).as_secs_f32() / (progress - first_progress) as f32
+										* (100 - progress) as f32
+								)));
+							}
+						}
+					)
+					.context("Couldn't transcribe audio")?;
+
+					reporter.report(Progress::Transcribing(ExtendedProgress::Done))?;
+
+					// Left out when it wasn't looked for, rather than saying there was none
+					if let Some(speech) = &transcript.speech {
+						fs::write(output_path.join("speech.json"), to_string(speech)?)?;
+					} else {
+						let _ = fs::remove_file(output_path.join("speech.json"));
+					}
+
+					let (segments, words, removals) = if settings.transcription.filter_hallucinations {
+						filter(
+							transcript.segments,
+							transcript.words,
+							transcript.speech.as_deref().unwrap_or_default()
+						)
+					} else {
+						(transcript.segments, transcript.words, vec![])
+					};
+
+					fs::write(output_path.join("filtered.json"), to_string(&removals)?)?;
+
+					let to_segment = |x: Utterance| Segment {
+						text: x.text,
+						start: x.start as f32 / 100.0,
+						end: x.end as f32 / 100.0,
+						probability: Some(x.probability),
+						speaker: None
+					};
+
+					let speakers = settings
+						.transcription
+						.diarize
+						.then(|| diarize(&samples, &segments))
+						.flatten();
+
+					let mut segments = segments.into_iter().map(to_segment).collect_vec();
+
+					for (segment, speaker) in segments.iter_mut().zip(speakers.into_iter().flatten()) {
+						segment.speaker = Some(speaker);
+					}
+
+					let mut words = words.into_iter().map(to_segment).collect_vec();
+
+					let corrections = correct(&mut segments, &mut words, &glossary);
+
+					fs::write(output_path.join("corrections.json"), to_string(&corrections)?)?;
+
+					(
+						segments,
+						Some(words),
+						Some(transcript.language),
+						transcript.translated,
+						false
+					)
+				}
+			})
+		},
+		|| {
+			anyhow::Ok({
+				reporter.report(Progress::Processing(ExtendedProgress::Preparing))?;
+
+				let mut roi = video_settings.roi.clone();
+
+				let overlay = match video_settings.overlay {
+					OverlayMask::Detect if detection.mask_overlays => {
+						Some(detect_overlay(video_path).context("Couldn't detect overlays")?)
+					}
+					OverlayMask::Detect => None,
+					OverlayMask::Off => Some(None),
+					OverlayMask::Manual(rect) => Some(Some(rect))
+				};
+
+				// Saved so the mask that was used can be shown and corrected
+				if let Some(overlay) = overlay {
+					fs::write(output_path.join("mask.json"), to_string(&overlay)?)?;
+
+					roi.exclusions.extend(overlay);
+				} else {
+					let _ = fs::remove_file(output_path.join("mask.json"));
+				}
+
+				let mut scanner = Scanner {
+					detector: detector.as_ref(),
+					roi: &roi,
+					threshold: detector.threshold()
+				};
+
+				let mut video = scanner.open(video_path)?;
+
+				let frame_rate = video.frame_rate();
+
+				let total_secs = video.duration()?.as_secs();
+
+				if detection.threshold == ThresholdMode::Adaptive {
+					// Compare frames as far apart as the scan will
+					let gap = match detection.scan_mode {
+						ScanMode::Sequential => 1.0 + 1.0 / frame_rate,
+						ScanMode::Sampled { interval } => interval
+					};
+
+					scanner.threshold = scanner
+						.estimate_threshold(&mut video, gap, detection.sensitivity)
+						.context("Couldn't estimate threshold")?;
+				}
+
+				// Chunks should be long enough that seeking into them is worth it
+				let chunks = num_cpus::get().min((total_secs / 60.0) as usize).max(1);
+
+				let chunk_progress = (0..chunks).map(|_| AtomicU32::new(0)).collect_vec();
+
+				let start_time = Instant::now();
+				let last_report = Mutex::new(Instant::now());
+
+				let changes =
+					scanner.scan_parallel(video_path, detection.scan_mode, total_secs, chunks, |chunk, time| {
+						chunk_progress[chunk].store((time * 1000.0) as u32, Ordering::Relaxed);
+
+						if let Ok(mut last_report) = last_report.try_lock() {
+							if Instant::now() - *last_report > Duration::from_millis(100) {
+								*last_report = Instant::now();
+
+								let time = chunk_progress.iter().map(|x| x.load(Ordering::Relaxed)).sum::<u32>() as f32
+									/ 1000.0;
+
+								reporter.report(Progress::Processing(ExtendedProgress::Progress(
+									time / total_secs,
+									(Instant::now() - start_time).as_secs_f32() / time * (total_secs - time)
+								)))?;
+							}
+						}
+
+						Ok(())
+					})?;
+
+				let mut splits = debounce(&changes);
+
+				splits.push(total_secs);
+
+				reporter.report(Progress::Processing(ExtendedProgress::Done))?;
+
+				reporter.report(Progress::GatheringPreviews(ExtendedProgress::Preparing))?;
+
+				// Previews are always taken at full resolution
+				let mut video = Decoder::new(video_path.to_owned()).context("Couldn't open video")?;
+
+				let middle_frames = splits
+					.iter()
+					.tuple_windows()
+					.map(|(start, end)| (start + end) / 2.0)
+					.map(|x| (x * frame_rate).round() as usize)
+					.collect_vec();
+
+				let frames_to_decode = *middle_frames.last().unwrap_or(&0) as f32;
+
+				let start_time = Instant::now();
+
+				// Indices of the splits each region is made of, more than one if a slide is built up step by step
+				let mut groups: Vec<Vec<usize>> = vec![];
+
+				// The latest build of the current region, as it'll be saved and as it's compared
+				let mut latest: Option<(Frame, Frame)> = None;
+
+				let save_preview = |slide_id: usize, frame: Frame| {
+					let x: Result<_> = try {
+						let frame = if video_settings.crop_previews {
+							video_settings.roi.crop(frame)
+						} else {
+							frame
+						};
+
+						let (height, width, _) = frame.dim();
+
+						let img: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_raw(
+							width as u32,
+							height as u32,
+							frame
+								.slice(ndarray::s![.., .., 0..3])
+								.to_slice()
+								.context("Couldn't slice frame as image")?
+								.to_vec()
+						)
+						.unwrap();
+
+						img.save(output_path.join(format!("{}.png", slide_id)))?;
+					};
+
+					if let Err(e) = x {
+						eprintln!("Error in saving preview image {slide_id}: {e}");
+					}
+				};
+
+				// Slides are recognised when they come back, so each one's preview is only saved once
+				let mut fingerprints: Vec<Option<Fingerprint>> = vec![];
+				let mut slide_ids = vec![];
+
+				let mut identify_slide = |latest: Option<(Frame, Frame)>| {
+					let Some((preview, comparable)) = latest else {
+						fingerprints.push(None);
+						return fingerprints.len() - 1;
+					};
+
+					let fingerprint = Fingerprint::new(&comparable);
+
+					if let Some(slide_id) = fingerprints
+						.iter()
+						.position(|x| x.is_some_and(|x| x.matches(&fingerprint)))
+					{
+						return slide_id;
+					}
+
+					fingerprints.push(Some(fingerprint));
+					save_preview(fingerprints.len() - 1, preview);
+
+					fingerprints.len() - 1
+				};
+
+				let mut frame = 0;
+				for (idx, middle_frame) in middle_frames.into_iter().enumerate() {
+					let x: Result<_> = try {
+						while frame != middle_frame {
+							video.decode_raw()?;
+							frame += 1;
+
+							reporter.report(Progress::GatheringPreviews(ExtendedProgress::Progress(
+								frame as f32 / frames_to_decode,
+								(Instant::now() - start_time).as_secs_f32() / frame as f32
+									* (frames_to_decode - frame as f32)
+							)))?;
+						}
+
+						video.decode()?.1
+					};
+
+					let decoded = x
+						.inspect_err(|e| eprintln!("Error in decoding preview frame {idx}: {e}"))
+						.ok()
+						.map(|frame| {
+							let comparable = roi.apply(frame.clone());
+							(frame, comparable)
+						});
+
+					let is_build = detection.merge_builds
+						&& matches!(
+							(&latest, &decoded),
+							(Some((_, previous)), Some((_, next))) if is_build_step(previous, next)
+						);
+
+					if is_build {
+						groups.last_mut().unwrap().push(idx);
+					} else {
+						if !groups.is_empty() {
+							slide_ids.push(identify_slide(latest.take()));
+						}
+
+						groups.push(vec![idx]);
+					}
+
+					latest = decoded;
+				}
+
+				if !groups.is_empty() {
+					slide_ids.push(identify_slide(latest));
+				}
+
+				reporter.report(Progress::GatheringPreviews(ExtendedProgress::Done))?;
+
+				(splits, groups, slide_ids, scanner.threshold)
+			})
+		}
+	);
+
+	let ((segments, words, language, translated, no_audio), (splits, groups, slide_ids, threshold)) = (a?, b?);
+
+	reporter.report(Progress::Summarising(ExtendedProgress::Preparing))?;
+
+	let mut split_segments = vec![];
+
+	for (group, slide_id) in groups.iter().zip(slide_ids) {
+		let (split_start, split_end) = (&splits[group[0]], &splits[group[group.len() - 1] + 1]);
+
+		let in_split = |x: &&Segment| {
+			(x.start >= *split_start && x.start <= *split_end) || (x.end >= *split_start && x.end <= *split_end)
+		};
+
+		let mut included_segments = segments.iter().filter(in_split).cloned().collect_vec();
+
+		// Remove [BLANK_AUDIO] tokens
+
+		if included_segments
+			.last()
+			.map(|Segment { text, .. }| text)
+			.unwrap_or(&String::new())
+			.trim()
+			.starts_with('[')
+		{
+			included_segments.pop();
+		}
+
+		let included_words = words.as_ref().map(|words| {
+			let mut included_words = words.iter().filter(in_split).cloned().collect_vec();
+
+			if included_words
+				.last()
+				.map(|Segment { text, .. }| text)
+				.unwrap_or(&String::new())
+				.trim()
+				.starts_with('[')
+			{
+				included_words.pop();
+			}
+
+			included_words
+		});
+
+		split_segments.push(Region {
+			start: *split_start,
+			end: *split_end,
+			summary: transcript_text(&included_segments),
+			segments: included_segments,
+			words: included_words,
+			builds: (group.len() > 1).then(|| group.iter().map(|idx| splits[*idx]).collect()),
+			slide_id
+		});
+	}
+
+	if settings.ai.use_ai {
+		let transcript_language = if translated {
+			Some("english")
+		} else {
+			language.as_deref().and_then(get_lang_id).and_then(get_lang_str_full)
+		};
+
+		let client = Client::new_with_base(&settings.ai.base_url, settings.ai.key.to_owned());
+
+		let start_time = Instant::now();
+
+		let total_segments = split_segments.len() as f32;
+
+		for (idx, region) in split_segments.iter_mut().enumerate() {
+			region.summary = region.summary.trim().to_owned();
+
+			if !region.summary.is_empty() {
+				if let Ok(res) = client
+					.chat()
+					.create(
+						ChatCompletionParametersBuilder::default()
+							.model(&settings.ai.model)
+							.messages(vec![ChatMessage::User {
+								content: ChatMessageContent::Text(
+									settings
+										.ai
+										.prompt_template
+										.replace("##text##", &region.summary)
+										.replace("##language##", transcript_language.unwrap_or("the same language"))
+								),
+								name: None
+							}])
+							.build()?
+					)
+					.await
+				{
+					if let ChatMessage::Assistant { content, .. } = &res.choices[0].message {
+						if let ChatMessageContent::Text(text) = content.as_ref().context("No response content")? {
+							if text.split("\n\n").next().context("No response content")?.ends_with(":") {
+								region.summary =
+									text.split("\n\n").skip(1).collect_vec().join("\n\n").trim().to_owned();
+							} else {
+								region.summary = text.trim().to_owned();
+							}
+						}
+					}
+
+					tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+				}
+			}
+
+			reporter.report(Progress::Summarising(ExtendedProgress::Progress(
+				(idx + 1) as f32 / total_segments,
+				(Instant::now() - start_time).as_secs_f32() / (idx + 1) as f32 * (total_segments - (idx + 1) as f32)
+			)))?;
+		}
+	}
+
+	fs::write(
+		output_path.join("metadata.json"),
+		to_string(&Metadata {
+			detector: detector.name().into(),
+			threshold,
+			language,
+			translated,
+			transcription_key: (!json_path.exists()).then(|| transcription_key(video_path, settings)),
+			no_audio
+		})?
+	)?;
+
+	fs::write(output_path.join("regions.json"), to_string(&split_segments)?)?;
+
+	reporter.report(Progress::Summarising(ExtendedProgress::Done))?;
+}
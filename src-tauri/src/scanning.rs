@@ -0,0 +1,338 @@
+use std::{ops::Range, path::Path};
+
+use anyhow::{Context, Result};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tryvial::try_fn;
+use video_rs::{
+	decode::{Decoder, DecoderBuilder},
+	resize::Resize,
+	Frame
+};
+
+use crate::detection::{PreparedFrame, RegionOfInterest, SlideChangeDetector};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum ScanMode {
+	/// Decode every frame, skipping a second after each stable one
+	#[default]
+	Sequential,
+	/// Seek to a keyframe every `interval` seconds and bisect between samples that differ
+	Sampled { interval: f32 }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum ThresholdMode {
+	/// Use the detector's own threshold
+	#[default]
+	Fixed,
+	/// Loosen the threshold to the video's frame-to-frame noise, for camera-filmed projectors and the like
+	Adaptive
+}
+
+/// What was found in one stretch of the video
+pub struct Scan {
+	/// Times at which the frame stops matching the one before it, starting with the first frame
+	pub changes: Vec<f32>,
+	first_frame: Option<PreparedFrame>,
+	last_frame: Option<PreparedFrame>
+}
+
+/// Compares frames with a detector after cropping and masking them
+pub struct Scanner<'a> {
+	pub detector: &'a dyn SlideChangeDetector,
+	pub roi: &'a RegionOfInterest,
+	pub threshold: f32
+}
+
+impl Scanner<'_> {
+	// Frames are prepared as they're decoded, so the previous frame is never worked over a second time when the next
+	// one is compared with it
+	fn is_change(&self, x: &PreparedFrame, y: &PreparedFrame) -> bool {
+		self.detector.prepared_similarity(x, y) < self.threshold
+	}
+
+	fn prepare(&self, frame: Frame) -> PreparedFrame {
+		self.detector.prepare(self.roi.apply(frame))
+	}
+
+	/// Estimates the noise in frame-to-frame similarity from pairs of frames `gap` seconds apart across the video, and
+	/// puts the threshold at the median plus a multiple of the median absolute deviation. Higher `sensitivity` uses a
+	/// smaller multiple and so splits more readily. Never stricter than the detector's own threshold.
+	#[try_fn]
+	pub fn estimate_threshold(&self, video: &mut Decoder, gap: f32, sensitivity: f32) -> Result<f32> {
+		const SAMPLES: usize = 64;
+
+		let duration = video.duration()?.as_secs();
+
+		let mut distances = vec![];
+
+		for idx in 0..SAMPLES {
+			let time = (duration - gap).max(0.0) * idx as f32 / SAMPLES as f32;
+
+			if let (Some((_, x)), Some((_, y))) = (
+				self.frame_at(video, time, true)?,
+				self.frame_at(video, time + gap, true)?
+			) {
+				distances.push(1.0 - self.detector.prepared_similarity(&x, &y));
+			}
+		}
+
+		if distances.is_empty() {
+			return Ok(self.detector.threshold());
+		}
+
+		let median = |values: &mut Vec<f32>| {
+			values.sort_by(f32::total_cmp);
+			values[values.len() / 2]
+		};
+
+		let median_distance = median(&mut distances);
+		let deviation = median(&mut distances.iter().map(|x| (x - median_distance).abs()).collect());
+
+		// 1.4826 scales the MAD to a standard deviation for normally distributed noise
+		let threshold = 1.0 - (median_distance + 5.0 / sensitivity.max(0.01) * 1.4826 * deviation);
+
+		threshold.min(self.detector.threshold())
+	}
+
+	/// Opens a decoder at the size the detector works at
+	#[try_fn]
+	pub fn open(&self, video_path: &Path) -> Result<Decoder> {
+		match self.detector.decode_size() {
+			Some((width, height)) => DecoderBuilder::new(video_path.to_owned())
+				.with_resize(Resize::Fit(width, height))
+				.build(),
+			None => Decoder::new(video_path.to_owned())
+		}
+		.context("Couldn't open video")?
+	}
+
+	/// Splits the video into `chunks` stretches that are scanned in parallel, each with its own decoder, and merges
+	/// the changes where they meet. `on_progress` gets the chunk index and how far into the chunk it is in seconds.
+	#[try_fn]
+	pub fn scan_parallel(
+		&self,
+		video_path: &Path,
+		mode: ScanMode,
+		duration: f32,
+		chunks: usize,
+		on_progress: impl Fn(usize, f32) -> Result<()> + Sync
+	) -> Result<Vec<f32>> {
+		let chunks = chunks.max(1);
+
+		let scans = (0..chunks)
+			.into_par_iter()
+			.map(|idx| {
+				let range = duration * idx as f32 / chunks as f32..duration * (idx + 1) as f32 / chunks as f32;
+
+				let mut video = self.open(video_path)?;
+
+				self.scan(&mut video, mode, range.clone(), |time| {
+					on_progress(idx, time - range.start)
+				})
+			})
+			.collect::<Result<Vec<_>>>()?;
+
+		let mut changes = vec![];
+		let mut previous: Option<PreparedFrame> = None;
+
+		for scan in scans {
+			let mut chunk_changes = scan.changes.into_iter();
+
+			// Every chunk starts with a change at its first frame, which only stands if it differs from where the
+			// previous chunk left off
+			if let (Some(previous), Some(first)) = (&previous, &scan.first_frame) {
+				if !self.is_change(previous, first) {
+					chunk_changes.next();
+				}
+			}
+
+			changes.extend(chunk_changes);
+
+			if scan.last_frame.is_some() {
+				previous = scan.last_frame;
+			}
+		}
+
+		changes
+	}
+
+	pub fn scan(
+		&self,
+		video: &mut Decoder,
+		mode: ScanMode,
+		range: Range<f32>,
+		on_progress: impl FnMut(f32) -> Result<()>
+	) -> Result<Scan> {
+		match mode {
+			ScanMode::Sequential => self.scan_sequential(video, range, on_progress),
+			ScanMode::Sampled { interval } => self.scan_sampled(video, interval, range, on_progress)
+		}
+	}
+
+	#[try_fn]
+	fn scan_sequential(
+		&self,
+		video: &mut Decoder,
+		range: Range<f32>,
+		mut on_progress: impl FnMut(f32) -> Result<()>
+	) -> Result<Scan> {
+		let frame_rate = video.frame_rate();
+
+		if range.start > 0.0 {
+			video.seek((range.start * 1000.0) as i64)?;
+		}
+
+		let mut changes = vec![];
+
+		let mut first_frame: Option<PreparedFrame> = None;
+		let mut last_frame: Option<PreparedFrame> = None;
+
+		while let Ok((time, frame)) = video.decode() {
+			let time = time.as_secs();
+
+			// Seeking lands on the keyframe before the start
+			if time < range.start {
+				continue;
+			}
+
+			if time >= range.end {
+				break;
+			}
+
+			let frame = self.prepare(frame);
+
+			on_progress(time)?;
+
+			if let Some(last_frame) = last_frame {
+				if self.is_change(&last_frame, &frame) {
+					changes.push(time);
+				} else {
+					// Skip one second
+					for _ in 0..(frame_rate) as usize {
+						let _ = video.decode_raw();
+					}
+				}
+			} else {
+				changes.push(time);
+				first_frame = Some(frame.clone());
+			}
+
+			last_frame = Some(frame);
+		}
+
+		Scan {
+			changes,
+			first_frame,
+			last_frame
+		}
+	}
+
+	#[try_fn]
+	fn scan_sampled(
+		&self,
+		video: &mut Decoder,
+		interval: f32,
+		range: Range<f32>,
+		mut on_progress: impl FnMut(f32) -> Result<()>
+	) -> Result<Scan> {
+		let frame_time = 1.0 / video.frame_rate();
+
+		let Some((mut last_time, mut last_frame)) = self.frame_at(video, range.start, true)? else {
+			return Ok(Scan {
+				changes: vec![],
+				first_frame: None,
+				last_frame: None
+			});
+		};
+
+		let first_frame = Some(last_frame.clone());
+
+		let mut changes = vec![last_time];
+
+		// The last sample is the first frame at or after the end, so changes right before the next chunk are found, but
+		// it only stands in for the chunk's last frame and changes from the end on are left to the next chunk
+		while last_time < range.end {
+			let target = (last_time + interval.max(frame_time)).min(range.end);
+
+			// Keyframes are cheap to reach, but fall back to an exact frame if the nearest one isn't past the last sample
+			let sample = match self.frame_at(video, target, false)? {
+				Some((time, frame)) if time > last_time + frame_time / 2.0 => Some((time, frame)),
+				_ => self.frame_at(video, target, true)?
+			};
+
+			let Some((time, frame)) = sample else {
+				break;
+			};
+
+			on_progress(time.min(range.end))?;
+
+			if !self.is_change(&last_frame, &frame) {
+				(last_time, last_frame) = (time.min(range.end), frame);
+				continue;
+			}
+
+			// Narrow down to the first frame that no longer matches the last sample
+			let (mut low, mut high, mut high_frame) = (last_time, time, frame);
+
+			while high - low > frame_time * 1.5 {
+				match self.frame_at(video, (low + high) / 2.0, true)? {
+					Some((time, frame)) if time < high => {
+						if self.is_change(&last_frame, &frame) {
+							(high, high_frame) = (time, frame);
+						} else {
+							low = time;
+						}
+					}
+
+					_ => break
+				}
+			}
+
+			if high >= range.end {
+				break;
+			}
+
+			changes.push(high);
+
+			(last_time, last_frame) = (high, high_frame);
+		}
+
+		Scan {
+			changes,
+			first_frame,
+			last_frame: Some(last_frame)
+		}
+	}
+
+	// Seeks to the keyframe before `target`, then decodes up to the first frame at or after it if `exact`
+	#[try_fn]
+	fn frame_at(&self, video: &mut Decoder, target: f32, exact: bool) -> Result<Option<(f32, PreparedFrame)>> {
+		// Seeking past the end just means there's no frame there
+		if video.seek((target * 1000.0) as i64).is_err() {
+			return Ok(None);
+		}
+
+		while let Ok((time, frame)) = video.decode() {
+			if !exact || time.as_secs() >= target {
+				return Ok(Some((time.as_secs(), self.prepare(frame))));
+			}
+		}
+
+		None
+	}
+}
+
+/// Drops changes that follow another within two seconds, since those are transitions rather than new slides
+pub fn debounce(changes: &[f32]) -> Vec<f32> {
+	changes
+		.iter()
+		.enumerate()
+		.filter(|(idx, time)| *idx == 0 || **time - changes[idx - 1] > 2.0)
+		.map(|(_, time)| *time)
+		.collect()
+}
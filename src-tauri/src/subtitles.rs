@@ -0,0 +1,127 @@
+extern crate ffmpeg_next as ffmpeg;
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ffmpeg::{codec, format, media, subtitle::Rect, Subtitle};
+use tryvial::try_fn;
+
+use crate::pipeline::Segment;
+
+// Text subtitle codecs; bitmap ones like DVD and PGS subtitles would need OCR
+static TEXT_CODECS: &[codec::Id] = &[
+	codec::Id::MOV_TEXT,
+	codec::Id::SUBRIP,
+	codec::Id::SRT,
+	codec::Id::WEBVTT,
+	codec::Id::ASS,
+	codec::Id::SSA,
+	codec::Id::TEXT
+];
+
+// Drops `{...}` override blocks and turns line breaks into spaces
+fn strip_ass(text: &str) -> String {
+	let mut stripped = String::new();
+	let mut depth = 0;
+
+	for x in text.chars() {
+		match x {
+			'{' => depth += 1,
+			'}' if depth > 0 => depth -= 1,
+			_ if depth == 0 => stripped.push(x),
+			_ => {}
+		}
+	}
+
+	stripped
+		.replace("\\N", " ")
+		.replace("\\n", " ")
+		.replace("\\h", " ")
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+// FFmpeg hands out text subtitles as ASS dialogue lines, with the text after the eighth comma
+fn ass_text(line: &str) -> String {
+	strip_ass(line.splitn(9, ',').nth(8).unwrap_or(line))
+}
+
+/// Reads the first text subtitle stream in the video, preferring one marked as default, as segments. Returns `None`
+/// if there isn't one.
+#[try_fn]
+pub fn read_subtitles(video_path: &Path) -> Result<Option<Vec<Segment>>> {
+	ffmpeg::init()?;
+
+	let mut ictx = format::input(video_path).context("Couldn't open video")?;
+
+	let Some(stream) = ictx
+		.streams()
+		.filter(|x| x.parameters().medium() == media::Type::Subtitle && TEXT_CODECS.contains(&x.parameters().id()))
+		.min_by_key(|x| !x.disposition().contains(format::stream::Disposition::DEFAULT))
+	else {
+		return Ok(None);
+	};
+
+	let index = stream.index();
+	let time_base = f64::from(stream.time_base());
+
+	let mut decoder = codec::context::Context::from_parameters(stream.parameters())?
+		.decoder()
+		.subtitle()
+		.context("Couldn't open subtitle decoder")?;
+
+	let mut segments: Vec<Segment> = vec![];
+
+	for (stream, packet) in ictx.packets() {
+		if stream.index() != index {
+			continue;
+		}
+
+		let Some(pts) = packet.pts() else {
+			continue;
+		};
+
+		let mut subtitle = Subtitle::new();
+
+		if !decoder.decode(&packet, &mut subtitle).unwrap_or(false) {
+			continue;
+		}
+
+		let text = subtitle
+			.rects()
+			.filter_map(|rect| match rect {
+				Rect::Text(x) => Some(strip_ass(x.get())),
+				Rect::Ass(x) => Some(ass_text(x.get())),
+				_ => None
+			})
+			.filter(|x| !x.is_empty())
+			.collect::<Vec<_>>()
+			.join(" ");
+
+		if text.is_empty() {
+			continue;
+		}
+
+		let start = pts as f64 * time_base + subtitle.start() as f64 / 1000.0;
+
+		// Some containers leave the packet duration out and only give the display time
+		let end = if packet.duration() > 0 {
+			(pts + packet.duration()) as f64 * time_base
+		} else {
+			pts as f64 * time_base + subtitle.end() as f64 / 1000.0
+		};
+
+		segments.push(Segment {
+			text: format!(" {text}"),
+			start: start as f32,
+			end: end.max(start) as f32,
+			probability: None,
+			speaker: None
+		});
+	}
+
+	segments.sort_by(|x, y| x.start.total_cmp(&y.start));
+
+	(!segments.is_empty()).then_some(segments)
+}
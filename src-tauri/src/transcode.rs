@@ -1,27 +1,73 @@
 extern crate ffmpeg_next as ffmpeg;
 
-use std::env;
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use ffmpeg::{codec, filter, format, frame, media};
-use ffmpeg::{rescale, Rescale};
 use ffmpeg_next::ChannelLayout;
+use serde::{Deserialize, Serialize};
+use specta::Type;
 use tryvial::try_fn;
 
-fn filter(
-	spec: &str,
-	decoder: &codec::decoder::Audio,
-	encoder: &codec::encoder::Audio
-) -> Result<filter::Graph, ffmpeg::Error> {
+/// Whisper's sample rate
+pub const SAMPLE_RATE: u32 = 16000;
+
+/// An audio stream in a video, like a lecturer's mic, the room or a dubbed language
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Type)]
+pub struct AudioStream {
+	pub index: usize,
+	// From the stream's tags, like "eng" and "Lecturer mic"
+	pub language: Option<String>,
+	pub title: Option<String>,
+	pub codec: String,
+	// Unknown if FFmpeg can't decode it
+	pub channels: Option<u16>,
+	// Transcribed unless another one is chosen
+	pub default: bool
+}
+
+/// The video's audio streams in the order they're stored
+#[try_fn]
+pub fn list_audio_streams(video_path: &Path) -> Result<Vec<AudioStream>> {
+	ffmpeg::init()?;
+
+	let ictx = format::input(video_path).context("Couldn't open video")?;
+
+	let best = ictx.streams().best(media::Type::Audio).map(|x| x.index());
+
+	ictx.streams()
+		.filter(|x| x.parameters().medium() == media::Type::Audio)
+		.map(|stream| AudioStream {
+			index: stream.index(),
+			language: stream.metadata().get("language").map(str::to_owned),
+			title: stream.metadata().get("title").map(str::to_owned),
+			codec: stream.parameters().id().name().to_owned(),
+			channels: codec::context::Context::from_parameters(stream.parameters())
+				.and_then(|x| x.decoder().audio())
+				.map(|x| x.channels())
+				.ok(),
+			default: best == Some(stream.index())
+		})
+		.collect()
+}
+
+// Converts whatever the decoder gives into 16 kHz mono floats
+fn filter(decoder: &codec::decoder::Audio) -> Result<filter::Graph, ffmpeg::Error> {
 	let mut filter = filter::Graph::new();
 
+	// Some containers leave the layout unset and only give the channel count
+	let channel_layout = if decoder.channel_layout().is_empty() {
+		ChannelLayout::default(decoder.channels() as i32)
+	} else {
+		decoder.channel_layout()
+	};
+
 	let args = format!(
 		"time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
 		decoder.time_base(),
 		decoder.rate(),
 		decoder.format().name(),
-		decoder.channel_layout().bits()
+		channel_layout.bits()
 	);
 
 	filter.add(&filter::find("abuffer").unwrap(), "in", &args)?;
@@ -30,186 +76,98 @@ fn filter(
 	{
 		let mut out = filter.get("out").unwrap();
 
-		out.set_sample_format(encoder.format());
+		out.set_sample_format(format::Sample::F32(format::sample::Type::Packed));
 		out.set_channel_layout(ChannelLayout::MONO);
-		out.set_sample_rate(16000);
+		out.set_sample_rate(SAMPLE_RATE);
 	}
 
-	filter.output("in", 0)?.input("out", 0)?.parse(spec)?;
+	// The sink's constraints make FFmpeg put a resampler in between
+	filter.output("in", 0)?.input("out", 0)?.parse("anull")?;
 	filter.validate()?;
 
-	println!("{}", filter.dump());
-
-	if let Some(codec) = encoder.codec() {
-		if !codec
-			.capabilities()
-			.contains(ffmpeg::codec::capabilities::Capabilities::VARIABLE_FRAME_SIZE)
-		{
-			filter.get("out").unwrap().sink().set_frame_size(encoder.frame_size());
-		}
-	}
-
 	Ok(filter)
 }
 
-struct Transcoder {
-	stream: usize,
-	filter: filter::Graph,
-	decoder: codec::decoder::Audio,
-	encoder: codec::encoder::Audio,
-	in_time_base: ffmpeg::Rational,
-	out_time_base: ffmpeg::Rational
-}
+// Moves everything the filter has ready into `samples`
+fn drain(filter: &mut filter::Graph, samples: &mut Vec<f32>) {
+	let mut filtered = frame::Audio::empty();
 
-fn transcoder<P: AsRef<Path> + ?Sized>(
-	ictx: &mut format::context::Input,
-	octx: &mut format::context::Output,
-	path: &P,
-	filter_spec: &str
-) -> Result<Transcoder, ffmpeg::Error> {
-	let input = ictx
-		.streams()
-		.best(media::Type::Audio)
-		.expect("could not find best audio stream");
-	let context = ffmpeg::codec::context::Context::from_parameters(input.parameters())?;
-	let mut decoder = context.decoder().audio()?;
-	let codec = ffmpeg::encoder::find(octx.format().codec(path, media::Type::Audio))
-		.expect("failed to find encoder")
-		.audio()?;
-	let global = octx
-		.format()
-		.flags()
-		.contains(ffmpeg::format::flag::Flags::GLOBAL_HEADER);
-
-	decoder.set_parameters(input.parameters())?;
-
-	let mut output = octx.add_stream(codec)?;
-	let context = ffmpeg::codec::context::Context::from_parameters(output.parameters())?;
-	let mut encoder = context.encoder().audio()?;
-
-	if global {
-		encoder.set_flags(ffmpeg::codec::flag::Flags::GLOBAL_HEADER);
+	while filter.get("out").unwrap().sink().frame(&mut filtered).is_ok() {
+		samples.extend_from_slice(filtered.plane::<f32>(0));
 	}
+}
 
-	encoder.set_rate(16000);
-	encoder.set_channel_layout(ChannelLayout::MONO);
-	// encoder.set_channels(1);
-	encoder.set_format(codec.formats().expect("unknown supported formats").next().unwrap());
-	encoder.set_bit_rate(decoder.bit_rate());
-	encoder.set_max_bit_rate(decoder.max_bit_rate());
-
-	encoder.set_time_base((1, decoder.rate() as i32));
-	output.set_time_base((1, decoder.rate() as i32));
-
-	let encoder = encoder.open_as(codec)?;
-	output.set_parameters(&encoder);
-
-	let filter = filter(filter_spec, &decoder, &encoder)?;
-
-	let in_time_base = decoder.time_base();
-	let out_time_base = output.time_base();
+// Stamps the frame with its best guess at a timestamp, which the resampler goes by, and passes it through the filter
+#[try_fn]
+fn resample(filter: &mut filter::Graph, decoded: &mut frame::Audio, samples: &mut Vec<f32>) -> Result<()> {
+	let timestamp = decoded.timestamp();
+	decoded.set_pts(timestamp);
 
-	Ok(Transcoder {
-		stream: input.index(),
-		filter,
-		decoder,
-		encoder,
-		in_time_base,
-		out_time_base
-	})
+	filter.get("in").unwrap().source().add(decoded)?;
+	drain(filter, samples);
 }
 
-impl Transcoder {
-	fn send_frame_to_encoder(&mut self, frame: &ffmpeg::Frame) {
-		self.encoder.send_frame(frame).unwrap();
-	}
+/// Decodes an audio stream of the input, the best one unless `index` is given, and resamples it to 16 kHz mono, as
+/// Whisper takes it, without going through a file. Returns `None` if the input has no audio at all.
+///
+/// The whole track is kept in memory, at 64 KB a second or about 690 MB for a three-hour lecture, since voice detection
+/// and speaker labelling look at all of it.
+#[try_fn]
+pub fn decode_audio(input: impl AsRef<Path>, index: Option<usize>) -> Result<Option<Vec<f32>>> {
+	ffmpeg::init()?;
 
-	fn send_eof_to_encoder(&mut self) {
-		self.encoder.send_eof().unwrap();
-	}
+	let mut ictx = format::input(&input).context("Couldn't open video")?;
 
-	fn receive_and_process_encoded_packets(&mut self, octx: &mut format::context::Output) {
-		let mut encoded = ffmpeg::Packet::empty();
-		while self.encoder.receive_packet(&mut encoded).is_ok() {
-			encoded.set_stream(0);
-			encoded.rescale_ts(self.in_time_base, self.out_time_base);
-			encoded.write_interleaved(octx).unwrap();
+	let stream = match index {
+		Some(index) => ictx
+			.stream(index)
+			.filter(|x| x.parameters().medium() == media::Type::Audio)
+			.with_context(|| format!("Couldn't find audio stream {index}"))?,
+		None => match ictx.streams().best(media::Type::Audio) {
+			Some(stream) => stream,
+			None => return Ok(None)
 		}
-	}
+	};
 
-	fn add_frame_to_filter(&mut self, frame: &ffmpeg::Frame) {
-		self.filter.get("in").unwrap().source().add(frame).unwrap();
-	}
+	let index = stream.index();
 
-	fn flush_filter(&mut self) {
-		self.filter.get("in").unwrap().source().flush().unwrap();
-	}
+	let mut decoder = codec::context::Context::from_parameters(stream.parameters())?
+		.decoder()
+		.audio()
+		.context("Couldn't open audio decoder")?;
 
-	fn get_and_process_filtered_frames(&mut self, octx: &mut format::context::Output) {
-		let mut filtered = frame::Audio::empty();
-		while self.filter.get("out").unwrap().sink().frame(&mut filtered).is_ok() {
-			self.send_frame_to_encoder(&filtered);
-			self.receive_and_process_encoded_packets(octx);
-		}
-	}
+	decoder.set_parameters(stream.parameters())?;
 
-	fn send_packet_to_decoder(&mut self, packet: &ffmpeg::Packet) {
-		self.decoder.send_packet(packet).unwrap();
-	}
+	let mut filter = filter(&decoder).context("Couldn't set up resampling")?;
 
-	fn send_eof_to_decoder(&mut self) {
-		self.decoder.send_eof().unwrap();
-	}
+	let mut samples = vec![];
+	let mut decoded = frame::Audio::empty();
 
-	fn receive_and_process_decoded_frames(&mut self, octx: &mut format::context::Output) {
-		let mut decoded = frame::Audio::empty();
-		while self.decoder.receive_frame(&mut decoded).is_ok() {
-			let timestamp = decoded.timestamp();
-			decoded.set_pts(timestamp);
-			self.add_frame_to_filter(&decoded);
-			self.get_and_process_filtered_frames(octx);
+	for (stream, mut packet) in ictx.packets() {
+		if stream.index() != index {
+			continue;
 		}
-	}
-}
 
-// Transcode the `best` audio stream of the input file into a the output file while applying a
-// given filter. If no filter was specified the stream gets copied (`anull` filter).
-//
-// Example 1: Transcode *.mp3 file to *.wmv while speeding it up
-// transcode-audio in.mp3 out.wmv "atempo=1.2"
-//
-// Example 2: Overlay an audio file
-// transcode-audio in.mp3 out.mp3 "amovie=overlay.mp3 [ov]; [in][ov] amerge [out]"
-//
-// Example 3: Seek to a specified position (in seconds)
-// transcode-audio in.mp3 out.mp3 anull 30
-#[try_fn]
-pub fn transcode(input: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<()> {
-	ffmpeg::init()?;
+		packet.rescale_ts(stream.time_base(), decoder.time_base());
 
-	let mut ictx = format::input(&input)?;
-	let mut octx = format::output(&output)?;
-	let mut transcoder = transcoder(&mut ictx, &mut octx, &output, "anull")?;
-
-	octx.set_metadata(ictx.metadata().to_owned());
-	octx.write_header()?;
+		// A corrupt packet only costs its own audio
+		if decoder.send_packet(&packet).is_err() {
+			continue;
+		}
 
-	for (stream, mut packet) in ictx.packets() {
-		if stream.index() == transcoder.stream {
-			packet.rescale_ts(stream.time_base(), transcoder.in_time_base);
-			transcoder.send_packet_to_decoder(&packet);
-			transcoder.receive_and_process_decoded_frames(&mut octx);
+		while decoder.receive_frame(&mut decoded).is_ok() {
+			resample(&mut filter, &mut decoded, &mut samples)?;
 		}
 	}
 
-	transcoder.send_eof_to_decoder();
-	transcoder.receive_and_process_decoded_frames(&mut octx);
+	decoder.send_eof()?;
 
-	transcoder.flush_filter();
-	transcoder.get_and_process_filtered_frames(&mut octx);
+	while decoder.receive_frame(&mut decoded).is_ok() {
+		resample(&mut filter, &mut decoded, &mut samples)?;
+	}
 
-	transcoder.send_eof_to_encoder();
-	transcoder.receive_and_process_encoded_packets(&mut octx);
+	filter.get("in").unwrap().source().flush()?;
+	drain(&mut filter, &mut samples);
 
-	octx.write_trailer()?;
+	Some(samples)
 }
@@ -0,0 +1,258 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::transcode::SAMPLE_RATE;
+
+// 30 ms
+const FRAME: usize = 480;
+
+// How far above the noise floor a frame has to be to count as speech, in dB
+const MARGIN: f32 = 10.0;
+// Frames quieter than this are silence however quiet the recording is, in dBFS
+const MIN_LEVEL: f32 = -55.0;
+
+// Shorter pauses are part of speech
+const MIN_SILENCE: f32 = 1.0;
+// Shorter bursts are clicks and bumps
+const MIN_SPEECH: f32 = 0.25;
+// Kept either side of speech so word edges aren't cut off
+const PADDING: f32 = 0.2;
+
+/// A stretch of speech in the original audio, in seconds
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct SpeechSpan {
+	pub start: f32,
+	pub end: f32
+}
+
+/// Where speech is in the audio, and how it's joined up so only speech is transcribed. The joined samples are never
+/// held whole, since they'd be a second copy of most of a lecture; windows of them are gathered as they're needed.
+pub struct Speech {
+	// Where each piece starts in the joined samples, and the span of the original it came from
+	pieces: Vec<(usize, Range<usize>)>
+}
+
+impl Speech {
+	/// Finds speech by comparing the energy of short frames to the recording's noise floor
+	pub fn detect(samples: &[f32]) -> Self {
+		let levels = samples
+			.chunks(FRAME)
+			.map(|frame| {
+				let power = frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32;
+
+				10.0 * power.max(1e-10).log10()
+			})
+			.collect::<Vec<_>>();
+
+		let mut sorted = levels.clone();
+		sorted.sort_by(f32::total_cmp);
+
+		// The quietest tenth of a lecture is room noise
+		let floor = sorted.get(sorted.len() / 10).copied().unwrap_or(MIN_LEVEL);
+		let threshold = (floor + MARGIN).max(MIN_LEVEL);
+
+		let frames = |secs: f32| (secs * SAMPLE_RATE as f32 / FRAME as f32).ceil() as usize;
+
+		let mut spans: Vec<Range<usize>> = vec![];
+
+		for (idx, level) in levels.iter().enumerate() {
+			if *level < threshold {
+				continue;
+			}
+
+			match spans.last_mut() {
+				Some(last) if idx - last.end < frames(MIN_SILENCE) => last.end = idx + 1,
+				_ => spans.push(idx..idx + 1)
+			}
+		}
+
+		let padding = (PADDING * SAMPLE_RATE as f32) as usize;
+
+		let mut ranges: Vec<Range<usize>> = vec![];
+
+		for span in spans.into_iter().filter(|x| x.len() >= frames(MIN_SPEECH)) {
+			let range = (span.start * FRAME).saturating_sub(padding)..(span.end * FRAME + padding).min(samples.len());
+
+			match ranges.last_mut() {
+				Some(last) if range.start <= last.end => last.end = range.end,
+				_ => ranges.push(range)
+			}
+		}
+
+		// Nothing stood out, so leave it to Whisper
+		if ranges.is_empty() {
+			ranges.push(0..samples.len());
+		}
+
+		let mut joined = 0;
+		let mut pieces = vec![];
+
+		for range in ranges {
+			pieces.push((joined, range.clone()));
+			joined += range.len();
+		}
+
+		Self { pieces }
+	}
+
+	/// Number of samples of speech
+	pub fn joined_len(&self) -> usize {
+		self.pieces.last().map_or(0, |(start, range)| start + range.len())
+	}
+
+	/// Copies `range` of the joined speech out of the original `samples`
+	pub fn gather(&self, samples: &[f32], range: Range<usize>) -> Vec<f32> {
+		self.pieces
+			.iter()
+			.filter_map(|(start, original)| {
+				let from = range.start.max(*start);
+				let to = range.end.min(start + original.len());
+
+				(from < to).then(|| &samples[original.start + from - start..original.start + to - start])
+			})
+			.flatten()
+			.copied()
+			.collect()
+	}
+
+	/// Maps a Whisper timestamp in the joined samples, in hundredths of a second, back to the original audio
+	pub fn to_original(&self, time: i64) -> i64 {
+		let position = time.max(0) as usize * SAMPLE_RATE as usize / 100;
+
+		let idx = self
+			.pieces
+			.partition_point(|(start, _)| *start <= position)
+			.saturating_sub(1);
+
+		let Some((start, range)) = self.pieces.get(idx) else {
+			return time;
+		};
+
+		let original = (range.start + (position - start)).min(range.end);
+
+		(original * 100 / SAMPLE_RATE as usize) as i64
+	}
+
+	pub fn spans(&self) -> Vec<SpeechSpan> {
+		self.pieces
+			.iter()
+			.map(|(_, range)| SpeechSpan {
+				start: range.start as f32 / SAMPLE_RATE as f32,
+				end: range.end as f32 / SAMPLE_RATE as f32
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SECOND: usize = SAMPLE_RATE as usize;
+
+	// Silence with a tone from each start to end frame in `tones`, so their edges line up with the frames measured
+	fn audio(frames: usize, tones: &[(usize, usize)]) -> Vec<f32> {
+		(0..frames * FRAME)
+			.map(|idx| {
+				if tones.iter().any(|(start, end)| (*start..*end).contains(&(idx / FRAME))) {
+					0.5 * (idx as f32 * 440.0 / SECOND as f32 * std::f32::consts::TAU).sin()
+				} else {
+					0.0
+				}
+			})
+			.collect()
+	}
+
+	// Speech from 3.0 s to 5.1 s and from 8.1 s to 10.2 s of 12 s, which padding makes 2.8 s to 5.3 s and 7.9 s to
+	// 10.4 s, each 2.5 s long
+	fn two_spans() -> Speech {
+		Speech::detect(&audio(400, &[(100, 170), (270, 340)]))
+	}
+
+	#[test]
+	fn finds_padded_spans() {
+		let speech = two_spans();
+
+		let spans = speech.spans();
+
+		assert_eq!(spans.len(), 2, "{spans:?}");
+		assert!(
+			(spans[0].start - 2.8).abs() < 0.01 && (spans[0].end - 5.3).abs() < 0.01,
+			"{spans:?}"
+		);
+		assert!(
+			(spans[1].start - 7.9).abs() < 0.01 && (spans[1].end - 10.4).abs() < 0.01,
+			"{spans:?}"
+		);
+		assert_eq!(speech.joined_len(), 5 * SECOND);
+	}
+
+	#[test]
+	fn gathers_joined_speech() {
+		let samples = audio(400, &[(100, 170), (270, 340)]);
+		let speech = two_spans();
+
+		let joined = [&samples[44800..84800], &samples[126400..166400]].concat();
+
+		assert_eq!(speech.gather(&samples, 0..speech.joined_len()), joined);
+		// Across where the pieces meet
+		assert_eq!(speech.gather(&samples, 30000..50000), joined[30000..50000]);
+		assert_eq!(speech.gather(&samples, 70000..90000), joined[70000..80000]);
+	}
+
+	#[test]
+	fn maps_times_inside_spans() {
+		let speech = two_spans();
+
+		assert_eq!(speech.to_original(100), 380);
+		assert_eq!(speech.to_original(300), 840);
+	}
+
+	#[test]
+	fn maps_times_at_span_edges() {
+		let speech = two_spans();
+
+		assert_eq!(speech.to_original(0), 280);
+		assert_eq!(speech.to_original(249), 529);
+		// Where the pieces meet belongs to the second one
+		assert_eq!(speech.to_original(250), 790);
+		assert_eq!(speech.to_original(500), 1040);
+	}
+
+	#[test]
+	fn clamps_times_outside_spans() {
+		let speech = two_spans();
+
+		assert_eq!(speech.to_original(600), 1040);
+		assert_eq!(speech.to_original(-10), 280);
+	}
+
+	#[test]
+	fn keeps_everything_when_all_silent() {
+		let speech = Speech::detect(&vec![0.0; 5 * SECOND]);
+
+		assert_eq!(speech.spans(), [SpeechSpan { start: 0.0, end: 5.0 }]);
+		assert_eq!(speech.joined_len(), 5 * SECOND);
+		assert_eq!(speech.to_original(123), 123);
+	}
+
+	#[test]
+	fn keeps_everything_without_silence() {
+		// 15 s
+		let speech = Speech::detect(&audio(500, &[(0, 500)]));
+
+		assert_eq!(speech.spans(), [SpeechSpan { start: 0.0, end: 15.0 }]);
+		assert_eq!(speech.to_original(0), 0);
+		assert_eq!(speech.to_original(250), 250);
+		assert_eq!(speech.to_original(500), 500);
+	}
+
+	#[test]
+	fn keeps_nothing_for_no_audio() {
+		let speech = Speech::detect(&[]);
+
+		assert_eq!(speech.spans(), [SpeechSpan { start: 0.0, end: 0.0 }]);
+		assert_eq!(speech.to_original(100), 0);
+	}
+}
@@ -1,91 +1,604 @@
 #![allow(clippy::uninlined_format_args)]
 
-use anyhow::{Context, Result};
-use hound::{SampleFormat, WavReader};
-use std::path::Path;
+use std::{
+	borrow::Cow,
+	cell::RefCell,
+	fs,
+	ops::Range,
+	path::{Path, PathBuf},
+	rc::Rc,
+	sync::{Arc, Mutex, Weak},
+	thread,
+	time::{Duration, Instant}
+};
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use specta::Type;
 use tryvial::try_fn;
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
+use whisper_rs::{get_lang_str, FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
+
+use crate::{
+	glossary::glossary_prompt,
+	transcode::SAMPLE_RATE,
+	vad::{Speech, SpeechSpan},
+	TranscriptionSettings
+};
+
+/// A segment or word, with times in hundredths of a second
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Utterance {
+	pub text: String,
+	pub start: i64,
+	pub end: i64,
+	// Mean probability of its tokens
+	pub probability: f32
+}
 
-fn parse_wav_file(path: &Path) -> Vec<i16> {
-	let reader = WavReader::open(path).expect("failed to read file");
+pub struct Transcript {
+	pub segments: Vec<Utterance>,
+	pub words: Vec<Utterance>,
+	pub language: String,
+	// Whether Whisper translated it into English
+	pub translated: bool,
+	// Where speech was found, if it was looked for
+	pub speech: Option<Vec<SpeechSpan>>
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Type)]
+#[serde(rename_all = "camelCase")]
+pub enum DecodingStrategy {
+	/// Take the likeliest token each step, out of `best_of` samples when the temperature is raised
+	Greedy { best_of: i32 },
+	/// Keep the `beam_size` likeliest sequences; slower but more accurate
+	BeamSearch { beam_size: i32, patience: f32 }
+}
 
-	if reader.spec().channels != 1 {
-		panic!("expected mono audio file");
+impl Default for DecodingStrategy {
+	fn default() -> Self {
+		Self::BeamSearch {
+			beam_size: 5,
+			patience: 1.0
+		}
 	}
-	if reader.spec().sample_format != SampleFormat::Int {
-		panic!("expected integer sample format");
+}
+
+// A model that hasn't been used for this long is unloaded to free its memory
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+struct Loaded {
+	path: PathBuf,
+	// GPU, flash attention and GPU device it was loaded with
+	params: (bool, bool, i32),
+	context: Arc<WhisperContext>,
+	used: Instant
+}
+
+/// Keeps the last model loaded between videos, since loading one from disk takes a while
+#[derive(Clone, Default)]
+pub struct ModelCache(Arc<Mutex<Option<Loaded>>>);
+
+impl ModelCache {
+	/// The loaded model if it's the same file loaded the same way, otherwise loads it in place of the last one
+	#[try_fn]
+	pub fn get(&self, model_path: &Path, params: WhisperContextParameters) -> Result<Arc<WhisperContext>> {
+		let key = (params.use_gpu, params.flash_attn, params.gpu_device);
+
+		let mut loaded = self.0.lock().unwrap();
+
+		if let Some(loaded) = loaded.as_mut().filter(|x| x.path == model_path && x.params == key) {
+			loaded.used = Instant::now();
+
+			return Ok(loaded.context.clone());
+		}
+
+		// Let go of the last one first so both aren't in memory at once
+		*loaded = None;
+
+		let context = Arc::new(
+			WhisperContext::new_with_params(
+				model_path.to_str().context("Couldn't interpret model path as string")?,
+				params
+			)
+			.context("failed to open model")?
+		);
+
+		*loaded = Some(Loaded {
+			path: model_path.to_owned(),
+			params: key,
+			context: context.clone(),
+			used: Instant::now()
+		});
+
+		self.unload_when_idle(Arc::downgrade(&context));
+
+		context
 	}
-	if reader.spec().sample_rate != 16000 {
-		panic!("expected 16KHz sample rate");
+
+	/// Unloads the model, like when another one is chosen
+	pub fn clear(&self) {
+		self.0.lock().unwrap().take();
 	}
-	if reader.spec().bits_per_sample != 16 {
-		panic!("expected 16 bits per sample");
+
+	// Watches the model until it's been idle long enough or something else took its place
+	fn unload_when_idle(&self, context: Weak<WhisperContext>) {
+		let cache = self.clone();
+
+		thread::spawn(move || loop {
+			thread::sleep(IDLE_TIMEOUT / 5);
+
+			let mut loaded = cache.0.lock().unwrap();
+
+			let Some(model) = loaded.as_mut().filter(|x| Arc::as_ptr(&x.context) == context.as_ptr()) else {
+				break;
+			};
+
+			// Someone's still transcribing with it
+			if Arc::strong_count(&model.context) > 1 {
+				model.used = Instant::now();
+			} else if model.used.elapsed() >= IDLE_TIMEOUT {
+				*loaded = None;
+				break;
+			}
+		});
 	}
+}
 
-	reader
-		.into_samples::<i16>()
-		.map(|x| x.expect("sample"))
-		.collect::<Vec<_>>()
+// Whisper goes through the audio in windows this long, so an interrupted run only loses the window it was on
+const WINDOW: usize = 10 * 60 * SAMPLE_RATE as usize;
+// Audio heard either side of a window, so words at its edges aren't cut off
+const OVERLAP: usize = 10 * SAMPLE_RATE as usize;
+
+// Windows transcribed so far, so an interrupted run can pick up where it stopped. Kept once they're all done, so
+// changing only what's done with the transcript after doesn't run Whisper again.
+#[derive(Serialize, Deserialize, Default)]
+struct Checkpoint {
+	// Which decoding settings and audio the windows are from
+	key: String,
+	language: Option<String>,
+	translated: bool,
+	// Segments and words each window heard, overlaps and all, with times in the samples given to Whisper
+	windows: Vec<(Vec<Utterance>, Vec<Utterance>)>
+}
+
+fn to_centis(samples: usize) -> i64 {
+	(samples * 100 / SAMPLE_RATE as usize) as i64
+}
+
+// Windows time the same word a little differently, so one within this of a word the last window kept is taken as the
+// same word heard again
+const SAME_WORD: i64 = 100;
+
+// Lower case without punctuation, so a word is recognised whether or not a window ended a sentence on it
+fn bare(word: &Utterance) -> String {
+	word.text
+		.trim()
+		.trim_matches(|x: char| !x.is_alphanumeric())
+		.to_lowercase()
+}
+
+// Each segment with its words, which Whisper times within the segment. A word on the edge between two goes with the
+// first, since that's where punctuation ends up.
+fn group(segments: Vec<Utterance>, words: Vec<Utterance>) -> Vec<(Utterance, Vec<Utterance>)> {
+	let mut grouped = segments.into_iter().map(|x| (x, vec![])).collect::<Vec<_>>();
+
+	for word in words {
+		let middle = (word.start + word.end) / 2;
+		// Words past the last segment go with it
+		let idx = grouped
+			.partition_point(|(x, _)| x.end < middle)
+			.min(grouped.len().saturating_sub(1));
+
+		if let Some((_, words)) = grouped.get_mut(idx) {
+			words.push(word);
+		}
+	}
+
+	grouped
+}
+
+/// Joins up the segments and words of windows transcribed with overlaps, all timed on one timeline, where window `idx`
+/// has the audio to itself from `idx * length`. Segments starting before that stay whole with the window before, and
+/// the window goes on from where the last of them ends, leaving out words that were already heard.
+fn stitch(windows: Vec<(Vec<Utterance>, Vec<Utterance>)>, length: i64) -> (Vec<Utterance>, Vec<Utterance>) {
+	let mut kept: Vec<(Utterance, Vec<Utterance>)> = vec![];
+
+	for (idx, (segments, words)) in windows.into_iter().enumerate() {
+		let start = idx as i64 * length;
+
+		// This window heard the rest too, with more context after it
+		kept.retain(|(x, _)| x.start < start);
+
+		let covered = kept.last().map_or(i64::MIN, |(x, _)| x.end);
+
+		// The words the windows could both have timed around the cut
+		let mut heard = kept
+			.iter()
+			.flat_map(|(_, words)| words)
+			.filter(|x| x.end >= covered.saturating_sub(2 * SAME_WORD))
+			.map(|x| (bare(x), x.start))
+			.collect::<Vec<_>>();
+
+		for (segment, words) in group(segments, words) {
+			let total = words.len();
+
+			let words = words
+				.into_iter()
+				.filter(|word| {
+					let middle = (word.start + word.end) / 2;
+
+					if middle < covered.saturating_sub(SAME_WORD) {
+						return false;
+					}
+
+					if word.start > covered.saturating_add(SAME_WORD) {
+						return true;
+					}
+
+					// Each word heard before only stands for one word here
+					match heard
+						.iter()
+						.position(|(text, start)| *text == bare(word) && (word.start - start).abs() <= SAME_WORD)
+					{
+						Some(idx) => {
+							heard.remove(idx);
+							false
+						}
+						None => true
+					}
+				})
+				.collect::<Vec<_>>();
+
+			let Some(first) = words.first() else {
+				continue;
+			};
+
+			// The start of the segment was kept with the window before
+			let segment = if words.len() < total {
+				Utterance {
+					text: words.iter().map(|x| x.text.as_str()).collect(),
+					start: first.start,
+					end: segment.end,
+					probability: words.iter().map(|x| x.probability).sum::<f32>() / words.len() as f32
+				}
+			} else {
+				segment
+			};
+
+			kept.push((segment, words));
+		}
+	}
+
+	let (segments, words): (Vec<_>, Vec<Vec<_>>) = kept.into_iter().unzip();
+
+	(segments, words.into_iter().flatten().collect())
 }
 
+/// Transcribes the samples in overlapping windows, saving each one to `checkpoint_path` as it's done. A checkpoint
+/// left by an earlier run with the same audio, told apart by `key`, and the same decoding settings is picked up from.
+/// The model is only loaded with `load_model` if there's something left to transcribe.
 #[try_fn]
 pub fn transcribe(
-	model_path: &str,
-	wav_path: impl AsRef<Path>,
+	load_model: impl FnOnce() -> Result<Arc<WhisperContext>>,
+	samples: &[f32],
+	settings: &TranscriptionSettings,
+	glossary: &[String],
+	checkpoint_path: &Path,
+	key: &str,
 	progress_callback: impl FnMut(i32) + 'static
-) -> Result<(Vec<(String, i64, i64)>, Vec<(String, i64, i64)>)> {
-	let original_samples = parse_wav_file(wav_path.as_ref());
-	let mut samples = vec![0.0f32; original_samples.len()];
-	whisper_rs::convert_integer_to_float_audio(&original_samples, &mut samples).context("failed to convert samples")?;
+) -> Result<Transcript> {
+	// Also found for the hallucination filter, which checks segments against it
+	let speech = (settings.vad || settings.filter_hallucinations).then(|| Speech::detect(samples));
 
-	let ctx = WhisperContext::new_with_params(model_path, WhisperContextParameters::default())
-		.context("failed to open model")?;
+	// Only speech goes to Whisper if skipping silence, since it takes a while over it and tends to make things up in it
+	let speech_only = speech.as_ref().filter(|_| settings.vad);
+	let len = speech_only.map_or(samples.len(), Speech::joined_len);
+	// Gathered a window at a time, rather than keeping a joined copy of the speech
+	let input = |range: Range<usize>| match speech_only {
+		Some(x) => Cow::Owned(x.gather(samples, range)),
+		None => Cow::Borrowed(&samples[range])
+	};
+	let to_original = |time: i64| speech_only.map_or(time, |x| x.to_original(time));
 
-	let mut params = FullParams::new(SamplingStrategy::BeamSearch {
-		beam_size: 5,
-		patience: 1.0
-	});
+	// Terms last, since Whisper drops the start of a prompt that's too long
+	let prompt = Some(settings.initial_prompt.trim().to_owned())
+		.filter(|x| !x.is_empty())
+		.into_iter()
+		.chain(glossary_prompt(glossary))
+		.collect::<Vec<_>>()
+		.join(" ");
+
+	let key = format!(
+		"{key}:{}:{}:{}",
+		settings.decoding_key(),
+		blake3::hash(prompt.as_bytes()),
+		len
+	);
+
+	let mut checkpoint = fs::read(checkpoint_path)
+		.ok()
+		.and_then(|x| serde_json::from_slice::<Checkpoint>(&x).ok())
+		.filter(|x| x.key == key)
+		.unwrap_or_else(|| Checkpoint {
+			key,
+			..Default::default()
+		});
+
+	if checkpoint.windows.len() < len.div_ceil(WINDOW).max(1) {
+		transcribe_windows(
+			&load_model()?,
+			len,
+			input,
+			settings,
+			&prompt,
+			&mut checkpoint,
+			checkpoint_path,
+			progress_callback
+		)?;
+	}
+
+	let map = |x: Utterance| Utterance {
+		start: to_original(x.start),
+		end: to_original(x.end),
+		..x
+	};
+
+	let (segments, words) = stitch(checkpoint.windows, to_centis(WINDOW));
+
+	Transcript {
+		segments: segments.into_iter().map(map).collect(),
+		words: words.into_iter().map(map).collect(),
+		language: checkpoint.language.context("failed to get language")?,
+		translated: checkpoint.translated,
+		speech: speech.as_ref().map(Speech::spans)
+	}
+}
+
+// Transcribes the windows `checkpoint` doesn't have yet
+#[try_fn]
+fn transcribe_windows<'a>(
+	ctx: &WhisperContext,
+	len: usize,
+	input: impl Fn(Range<usize>) -> Cow<'a, [f32]>,
+	settings: &TranscriptionSettings,
+	prompt: &str,
+	checkpoint: &mut Checkpoint,
+	checkpoint_path: &Path,
+	progress_callback: impl FnMut(i32) + 'static
+) -> Result<()> {
+	let multilingual = ctx.is_multilingual();
+	let language = settings.language.as_deref().filter(|x| *x != "auto");
+
+	ensure!(
+		multilingual || language.is_none_or(|x| x == "en"),
+		"English-only models can't transcribe other languages, choose a multilingual model"
+	);
 
-	params.set_print_special(false);
-	params.set_print_progress(false);
-	params.set_print_realtime(false);
-	params.set_print_timestamps(false);
-	params.set_token_timestamps(true);
-	params.set_split_on_word(true);
-	params.set_entropy_thold(2.8);
+	// English-only models weren't trained to detect the language or to translate, and already give English
+	let language = if multilingual { language } else { Some("en") };
+	let translate = settings.translate && multilingual;
 
-	params.set_n_threads(num_cpus::get() as i32);
+	checkpoint.translated = translate;
 
-	params.set_progress_callback_safe(progress_callback);
+	let count = len.div_ceil(WINDOW).max(1);
+	let progress_callback = Rc::new(RefCell::new(progress_callback));
 
 	let mut state = ctx.create_state().context("failed to create key")?;
 
-	state.full(params, &samples).context("failed to convert samples")?;
+	for idx in checkpoint.windows.len()..count {
+		let start = (idx * WINDOW).saturating_sub(OVERLAP);
+		let end = ((idx + 1) * WINDOW + OVERLAP).min(len);
+		let offset = to_centis(start);
+
+		let mut params = FullParams::new(match settings.strategy {
+			DecodingStrategy::Greedy { best_of } => SamplingStrategy::Greedy { best_of },
+			DecodingStrategy::BeamSearch { beam_size, patience } => SamplingStrategy::BeamSearch { beam_size, patience }
+		});
+
+		params.set_print_special(false);
+		params.set_print_progress(false);
+		params.set_print_realtime(false);
+		params.set_print_timestamps(false);
+		params.set_token_timestamps(true);
+		params.set_split_on_word(true);
+		params.set_max_len(settings.max_len as i32);
+		params.set_temperature(settings.temperature);
+		params.set_temperature_inc(settings.temperature_inc);
+		params.set_entropy_thold(settings.entropy_thold);
+		params.set_logprob_thold(settings.logprob_thold);
+		params.set_no_speech_thold(settings.no_speech_thold);
 
-	let num_segments = state.full_n_segments().expect("failed to get segments");
+		if !prompt.is_empty() {
+			params.set_initial_prompt(prompt);
+		}
+
+		// Detect the language from the first 30 seconds unless it's given, then stick to it for the other windows
+		params.set_language(Some(checkpoint.language.as_deref().or(language).unwrap_or("auto")));
+		params.set_translate(translate);
+
+		params.set_n_threads(settings.threads.map_or(num_cpus::get(), |x| x as usize) as i32);
+
+		params.set_progress_callback_safe({
+			let progress_callback = progress_callback.clone();
+
+			move |progress: i32| (progress_callback.borrow_mut())((idx as i32 * 100 + progress) / count as i32)
+		});
+
+		state
+			.full(params, &input(start..end))
+			.context("failed to convert samples")?;
+
+		let num_segments = state.full_n_segments().expect("failed to get segments");
+
+		let mut words = Vec::new();
+		let mut utterances = Vec::new();
+		for segment_idx in 0..num_segments {
+			let text = state.full_get_segment_text(segment_idx)?;
+			let start = state.full_get_segment_t0(segment_idx)? + offset;
+			let stop = state.full_get_segment_t1(segment_idx)? + offset;
 
-	let mut words = Vec::new();
-	let mut utterances = Vec::new();
-	for segment_idx in 0..num_segments {
-		let text = state.full_get_segment_text(segment_idx)?;
-		let start = state.full_get_segment_t0(segment_idx)?;
-		let stop = state.full_get_segment_t1(segment_idx)?;
+			let num_tokens = state.full_n_tokens(segment_idx)?;
 
-		utterances.push((text, start, stop));
+			let mut tokens = vec![];
 
-		let num_tokens = state.full_n_tokens(segment_idx)?;
+			for t in 0..num_tokens {
+				let text = state.full_get_token_text(segment_idx, t)?;
+				let token_data = state.full_get_token_data(segment_idx, t)?;
 
-		for t in 0..num_tokens {
-			let text = state.full_get_token_text(segment_idx, t)?;
-			let token_data = state.full_get_token_data(segment_idx, t)?;
+				if text.starts_with("[_") {
+					continue;
+				}
 
-			if text.starts_with("[_") {
+				tokens.push(Utterance {
+					text,
+					start: token_data.t0 + offset,
+					end: token_data.t1 + offset,
+					probability: token_data.p
+				});
+			}
+
+			if tokens.is_empty() {
 				continue;
 			}
 
-			words.push((text, token_data.t0, token_data.t1));
+			utterances.push(Utterance {
+				text,
+				start,
+				end: stop,
+				probability: tokens.iter().map(|x| x.probability).sum::<f32>() / tokens.len() as f32
+			});
+
+			words.extend(tokens);
+		}
+
+		if checkpoint.language.is_none() {
+			checkpoint.language = Some(
+				get_lang_str(state.full_lang_id_from_state()?)
+					.context("failed to get language")?
+					.to_owned()
+			);
 		}
+
+		checkpoint.windows.push((utterances, words));
+
+		fs::write(checkpoint_path, serde_json::to_string(&checkpoint)?).context("Couldn't save checkpoint")?;
 	}
+}
 
-	(utterances, words)
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn utterance(text: &str, start: i64, end: i64) -> Utterance {
+		Utterance {
+			text: text.into(),
+			start,
+			end,
+			probability: 0.9
+		}
+	}
+
+	// A segment of the words given with their start and end
+	fn segment(words: &[(&str, i64, i64)]) -> (Utterance, Vec<Utterance>) {
+		let words = words
+			.iter()
+			.map(|(text, start, end)| utterance(text, *start, *end))
+			.collect::<Vec<_>>();
+
+		(
+			utterance(
+				&words.iter().map(|x| x.text.as_str()).collect::<String>(),
+				words[0].start,
+				words.last().unwrap().end
+			),
+			words
+		)
+	}
+
+	fn window(segments: &[&[(&str, i64, i64)]]) -> (Vec<Utterance>, Vec<Utterance>) {
+		let (segments, words): (Vec<_>, Vec<Vec<_>>) = segments.iter().map(|x| segment(x)).unzip();
+
+		(segments, words.concat())
+	}
+
+	fn texts(utterances: &[Utterance]) -> Vec<&str> {
+		utterances.iter().map(|x| x.text.as_str()).collect()
+	}
+
+	#[test]
+	fn keeps_boundary_word_once() {
+		let windows = vec![
+			window(&[
+				&[(" one", 900, 930), (" two", 930, 960), (" three", 960, 990)],
+				&[(" four", 990, 1000), (" five.", 1000, 1010)],
+				// Cut off at the end of the window
+				&[(" six", 1010, 1020)]
+			]),
+			window(&[
+				&[(" three", 965, 990), (" four", 992, 1001), (" five.", 1001, 1012)],
+				&[(" six", 1012, 1030), (" seven", 1030, 1060)]
+			]),
+		];
+
+		let (segments, words) = stitch(windows, 1000);
+
+		assert_eq!(texts(&segments), [" one two three", " four five.", " six seven"]);
+		assert_eq!(
+			texts(&words),
+			[" one", " two", " three", " four", " five.", " six", " seven"]
+		);
+	}
+
+	#[test]
+	fn keeps_word_timed_later_once() {
+		let windows = vec![
+			window(&[&[(" the", 960, 980), (" end.", 980, 1000)]]),
+			window(&[&[(" end", 1020, 1040), (" Next", 1050, 1080), (" part", 1080, 1100)]]),
+		];
+
+		let (segments, words) = stitch(windows, 1000);
+
+		assert_eq!(texts(&segments), [" the end.", " Next part"]);
+		assert_eq!(texts(&words), [" the", " end.", " Next", " part"]);
+		assert_eq!(segments[1].start, 1050);
+	}
+
+	#[test]
+	fn keeps_word_timed_earlier() {
+		let windows = vec![
+			window(&[&[(" the", 960, 980), (" end.", 980, 1000)], &[(" Next", 1000, 1010)]]),
+			// Heard a little earlier, before where the window before left off
+			window(&[&[(" Next", 995, 1002), (" part", 1002, 1030)]]),
+		];
+
+		let (segments, words) = stitch(windows, 1000);
+
+		assert_eq!(texts(&segments), [" the end.", " Next part"]);
+		assert_eq!(texts(&words), [" the", " end.", " Next", " part"]);
+	}
+
+	#[test]
+	fn leaves_segment_across_boundary_whole() {
+		let windows = vec![
+			window(&[&[(" right", 980, 1000), (" across", 1000, 1020), (" here.", 1020, 1040)]]),
+			window(&[
+				&[(" across", 1002, 1018), (" here.", 1018, 1041)],
+				&[(" And", 1045, 1060), (" on.", 1060, 1080)]
+			]),
+		];
+
+		let (segments, words) = stitch(windows, 1000);
+
+		assert_eq!(texts(&segments), [" right across here.", " And on."]);
+		assert_eq!(texts(&words), [" right", " across", " here.", " And", " on."]);
+	}
+
+	#[test]
+	fn leaves_single_window_alone() {
+		let windows = vec![window(&[&[(" just", 0, 20), (" this", 20, 40)], &[(" and", 40, 60)]])];
+
+		let (segments, words) = stitch(windows.clone(), 1000);
+
+		assert_eq!((segments, words), windows[0]);
+	}
 }
@@ -0,0 +1,175 @@
+use std::{fs, net::SocketAddr, path::Path};
+
+use anyhow::Result;
+use app_lib::{
+	models::{download, part_path},
+	pipeline::Reporter,
+	Progress
+};
+use sha1::{Digest, Sha1};
+use tauri::async_runtime;
+use tempfile::tempdir;
+use warp::{http::StatusCode, Filter};
+
+struct NoReporter;
+
+impl Reporter for NoReporter {
+	fn report(&self, _: Progress) -> Result<()> {
+		Ok(())
+	}
+}
+
+fn contents() -> Vec<u8> {
+	(0..1_000_000u32).map(|x| (x * 7 % 251) as u8).collect()
+}
+
+fn hash(data: &[u8]) -> String {
+	format!("{:x}", Sha1::digest(data))
+}
+
+// Serves `file` from `/model.bin`, with range support
+async fn serve(file: &Path) -> SocketAddr {
+	let (address, server) =
+		warp::serve(warp::path("model.bin").and(warp::fs::file(file.to_owned()))).bind_ephemeral(([127, 0, 0, 1], 0));
+
+	async_runtime::spawn(server);
+
+	address
+}
+
+// Serves `data` in full whatever range is asked for
+async fn serve_ignoring_range(data: Vec<u8>) -> SocketAddr {
+	let (address, server) =
+		warp::serve(warp::path("model.bin").map(move || data.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+
+	async_runtime::spawn(server);
+
+	address
+}
+
+#[test]
+fn downloads_and_verifies() {
+	let folder = tempdir().unwrap();
+	let data = contents();
+
+	let source = folder.path().join("source.bin");
+	fs::write(&source, &data).unwrap();
+
+	let path = folder.path().join("model.bin");
+
+	let result = async_runtime::block_on(async {
+		let address = serve(&source).await;
+
+		download(&format!("http://{address}/model.bin"), &path, &hash(&data), &NoReporter).await
+	});
+
+	result.unwrap();
+	assert_eq!(fs::read(&path).unwrap(), data);
+	assert!(!part_path(&path).exists());
+}
+
+#[test]
+fn resumes_partial_download() {
+	let folder = tempdir().unwrap();
+	let data = contents();
+
+	let source = folder.path().join("source.bin");
+	fs::write(&source, &data).unwrap();
+
+	let path = folder.path().join("model.bin");
+	fs::write(part_path(&path), &data[..data.len() / 3]).unwrap();
+
+	let result = async_runtime::block_on(async {
+		let address = serve(&source).await;
+
+		download(&format!("http://{address}/model.bin"), &path, &hash(&data), &NoReporter).await
+	});
+
+	result.unwrap();
+	assert_eq!(fs::read(&path).unwrap(), data);
+}
+
+#[test]
+fn finishes_complete_partial_download() {
+	let folder = tempdir().unwrap();
+	let data = contents();
+
+	let source = folder.path().join("source.bin");
+	fs::write(&source, &data).unwrap();
+
+	let path = folder.path().join("model.bin");
+	fs::write(part_path(&path), &data).unwrap();
+
+	let result = async_runtime::block_on(async {
+		let address = serve(&source).await;
+
+		download(&format!("http://{address}/model.bin"), &path, &hash(&data), &NoReporter).await
+	});
+
+	result.unwrap();
+	assert_eq!(fs::read(&path).unwrap(), data);
+}
+
+#[test]
+fn restarts_when_range_is_ignored() {
+	let folder = tempdir().unwrap();
+	let data = contents();
+
+	let path = folder.path().join("model.bin");
+	fs::write(part_path(&path), &data[..data.len() / 2]).unwrap();
+
+	let result = async_runtime::block_on(async {
+		let address = serve_ignoring_range(data.clone()).await;
+
+		download(&format!("http://{address}/model.bin"), &path, &hash(&data), &NoReporter).await
+	});
+
+	result.unwrap();
+	assert_eq!(fs::read(&path).unwrap(), data);
+}
+
+#[test]
+fn rejects_hash_mismatch() {
+	let folder = tempdir().unwrap();
+	let data = contents();
+
+	let source = folder.path().join("source.bin");
+	fs::write(&source, &data).unwrap();
+
+	let path = folder.path().join("model.bin");
+
+	let result = async_runtime::block_on(async {
+		let address = serve(&source).await;
+
+		download(
+			&format!("http://{address}/model.bin"),
+			&path,
+			&hash(b"something else"),
+			&NoReporter
+		)
+		.await
+	});
+
+	assert!(result.is_err());
+	assert!(!path.exists());
+	assert!(!part_path(&path).exists());
+}
+
+#[test]
+fn reports_server_errors() {
+	let folder = tempdir().unwrap();
+
+	let path = folder.path().join("model.bin");
+
+	let result = async_runtime::block_on(async {
+		let (address, server) =
+			warp::serve(warp::any().map(|| StatusCode::NOT_FOUND)).bind_ephemeral(([127, 0, 0, 1], 0));
+
+		async_runtime::spawn(server);
+
+		download(&format!("http://{address}/model.bin"), &path, &hash(&[]), &NoReporter).await
+	});
+
+	assert!(result.is_err());
+	assert!(!path.exists());
+}
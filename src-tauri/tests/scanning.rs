@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use anyhow::Result;
+use app_lib::{
+	detection::{DeltaE, RegionOfInterest},
+	scanning::{ScanMode, Scanner}
+};
+use ndarray::Array3;
+use tempfile::tempdir;
+use video_rs::{
+	encode::{Encoder, Settings},
+	time::Time
+};
+
+const FRAME_RATE: usize = 25;
+
+// Four seconds of black that turns white at exactly two seconds
+fn write_video(path: &Path) -> Result<()> {
+	video_rs::init()?;
+
+	let mut encoder = Encoder::new(path, Settings::preset_h264_yuv420p(160, 96, false))?;
+
+	let frame_time = Time::from_nth_of_a_second(FRAME_RATE);
+	let mut position = Time::zero();
+
+	for idx in 0..4 * FRAME_RATE {
+		let colour = if idx < 2 * FRAME_RATE { 0 } else { 255 };
+
+		encoder.encode(&Array3::from_elem((96, 160, 3), colour), position)?;
+		position = position.aligned_with(frame_time).add();
+	}
+
+	encoder.finish()?;
+
+	Ok(())
+}
+
+fn scan_split_at_change(mode: ScanMode) -> Result<Vec<f32>> {
+	let dir = tempdir()?;
+	let path = dir.path().join("video.mp4");
+
+	write_video(&path)?;
+
+	let roi = RegionOfInterest::default();
+	let scanner = Scanner {
+		detector: &DeltaE,
+		roi: &roi,
+		threshold: 0.99
+	};
+
+	// Two chunks meet at two seconds, right where the frame changes
+	scanner.scan_parallel(&path, mode, 4.0, 2, |_, _| Ok(()))
+}
+
+fn assert_found_once(changes: &[f32]) {
+	assert_eq!(changes.len(), 2, "{changes:?}");
+	assert!(changes[0].abs() < 0.01, "{changes:?}");
+	assert!((changes[1] - 2.0).abs() < 0.01, "{changes:?}");
+}
+
+#[test]
+fn sequential_change_on_chunk_boundary_is_found_once() -> Result<()> {
+	assert_found_once(&scan_split_at_change(ScanMode::Sequential)?);
+
+	Ok(())
+}
+
+#[test]
+fn sampled_change_on_chunk_boundary_is_found_once() -> Result<()> {
+	assert_found_once(&scan_split_at_change(ScanMode::Sampled { interval: 0.5 })?);
+
+	Ok(())
+}
@@ -10,8 +10,8 @@ declare global {
 // Function avoids 'window not defined' in SSR
 const invoke = () => window.__TAURI_INVOKE__;
 
-export function rsProcessRegions(videoPath: string) {
-    return invoke()<null>("rs_process_regions", { videoPath })
+export function rsProcessRegions(videoPath: string, force: boolean) {
+    return invoke()<null>("rs_process_regions", { videoPath,force })
 }
 
 export function rsSaveCurrentTime(dataPath: string, time: number) {
@@ -26,5 +26,29 @@ export function rsSaveSettings(settings: AppSettings) {
     return invoke()<null>("rs_save_settings", { settings })
 }
 
-export type AppSettings = { ai: AISettings }
+export function rsListModels() {
+    return invoke()<ModelEntry[]>("rs_list_models")
+}
+
+export function rsImportModel(modelPath: string) {
+    return invoke()<string>("rs_import_model", { modelPath })
+}
+
+export function rsListAudioStreams(videoPath: string) {
+    return invoke()<AudioStream[]>("rs_list_audio_streams", { videoPath })
+}
+
+export type AppSettings = { ai: AISettings; detection: DetectionSettings; transcription: TranscriptionSettings; videos: { [key: string]: VideoSettings } }
+export type DetectionSettings = { detector: Detector; mask_overlays: boolean; scan_mode: ScanMode; threshold: ThresholdMode; sensitivity: number; merge_builds: boolean }
+export type ScanMode = "sequential" | { sampled: { interval: number } }
+export type ThresholdMode = "fixed" | "adaptive"
+export type Detector = "deltaE" | { perceptualHash: { max_distance: number } }
+export type TranscriptionSettings = { model: string; language: string | null; translate: boolean; strategy: DecodingStrategy; temperature: number; temperature_inc: number; entropy_thold: number; logprob_thold: number; no_speech_thold: number; threads: number | null; max_len: number; initial_prompt: string; vad: boolean; filter_hallucinations: boolean; glossary: string[]; prefer_embedded_subtitles: boolean; diarize: boolean }
+export type DecodingStrategy = { greedy: { best_of: number } } | { beamSearch: { beam_size: number; patience: number } }
+export type ModelEntry = { name: string; size_mb: number; multilingual: boolean; installed: boolean; custom: boolean }
+export type VideoSettings = { detection: DetectionSettings | null; roi: RegionOfInterest; crop_previews: boolean; overlay: OverlayMask; audio_stream: number | null }
+export type OverlayMask = "detect" | "off" | { manual: Rect }
+export type RegionOfInterest = { crop: Rect | null; exclusions: Rect[] }
+export type Rect = { x: number; y: number; width: number; height: number }
 export type AISettings = { use_ai: boolean; base_url: string; key: string; model: string; prompt_template: string }
+export type AudioStream = { index: number; language: string | null; title: string | null; codec: string; channels: number | null; default: boolean }